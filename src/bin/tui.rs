@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::style::Style;
+use ratatui::widgets::{Block, Clear, List, ListState, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use resource_island_server::client::{ClientError, ClientOptions, GameClient};
+use resource_island_server::dtos::{GameStateResponse, PlayerInfoResponse};
+use resource_island_server::enums::{ActionOutcome, AttackTarget, BankAction, BidAction, Building, InvestmentAction, Items, PlayerToServerMessage, ServerBroadcastMessage, ServerToPlayerMessage, StateDiff};
+use resource_island_server::locale::Locale;
+use resource_island_server::rules::{PHASE_BID, PHASE_INVESTMENT, PHASE_SETTLEMENT, PHASE_TAKE};
+use tokio::sync::mpsc;
+
+/// 日志最多保留的行数
+const LOG_LIMIT: usize = 500;
+const HELP: &str = "Enter/m 行动菜单  r 准备  u 取消准备  s 开始  p 快照  d 原始帧  q 退出";
+
+/// 表单里已经填好的一项
+#[derive(Clone)]
+enum Answer {
+    Item(Items),
+    Building(Building),
+    Player(String),
+    Aim(AttackTarget),
+    Number(u32),
+}
+enum Field {
+    Choose(Vec<(String, Answer)>),
+    Number,
+}
+/// 菜单里的一个行动：依次填写各项，最后由 `build` 组装成消息
+struct Form {
+    title: String,
+    fields: Vec<(&'static str, Field)>,
+    answers: Vec<Answer>,
+    build: fn(&[Answer]) -> PlayerToServerMessage,
+}
+enum Mode {
+    Normal,
+    Menu { forms: Vec<Form>, selected: ListState },
+    Form { form: Form, selected: ListState, buffer: String },
+}
+struct App {
+    player_name: String,
+    state: GameStateResponse,
+    me: Option<PlayerInfoResponse>,
+    log: Vec<String>,
+    /// 打开后把收发的每一帧原样记进日志
+    raw_frames: bool,
+    mode: Mode,
+    quit: bool,
+}
+impl App {
+    fn name(&self, id: &str) -> String {
+        self.state.names.get(id).map(|x| x.to_string()).unwrap_or_else(|| id.to_string())
+    }
+    fn push_log(&mut self, line: String) {
+        self.log.push(line);
+        if self.log.len() > LOG_LIMIT {
+            self.log.remove(0);
+        }
+    }
+    fn phase_name(&self) -> &'static str {
+        if !self.state.started {
+            return "大厅";
+        }
+        match self.state.phase {
+            PHASE_BID => "出价",
+            PHASE_TAKE => "拿取",
+            PHASE_INVESTMENT => "投资",
+            PHASE_SETTLEMENT => "结算",
+            _ => "未知",
+        }
+    }
+    fn on_message(&mut self, msg: ServerToPlayerMessage) {
+        if self.raw_frames {
+            self.push_log(format!("<- {}", serde_json::to_string(&msg).unwrap_or_default()));
+        }
+        match msg {
+            ServerToPlayerMessage::Welcome { server_version, protocol_version, session_token, .. } => {
+                self.push_log(format!("已连接 {} (协议 {})，会话 {}", server_version, protocol_version, session_token));
+            }
+            ServerToPlayerMessage::Broadcast { raw } => {
+                match &raw {
+                    ServerBroadcastMessage::GameStart => self.state.started = true,
+                    ServerBroadcastMessage::WaitingOn { players } => self.state.waiting_on = players.clone(),
+                    _ => {}
+                }
+                self.push_log(format!("广播 {}", serde_json::to_string(&raw).unwrap_or_default()));
+            }
+            ServerToPlayerMessage::Snapshot { state, player, .. } => {
+                self.state = state;
+                if player.is_some() {
+                    self.me = player;
+                }
+            }
+            ServerToPlayerMessage::StateDiff { diffs, .. } => {
+                for diff in diffs {
+                    self.apply_diff(diff);
+                }
+            }
+            ServerToPlayerMessage::ActionAccepted { outcome: ActionOutcome::Explored { event, .. } } => {
+                self.push_log(format!("探索 {}", self.name(event.as_str())));
+            }
+            ServerToPlayerMessage::ActionAccepted { outcome } => {
+                self.push_log(format!("成功 {}", serde_json::to_string(&outcome).unwrap_or_default()));
+            }
+            ServerToPlayerMessage::ActionRejected { reason } => self.push_log(format!("失败 {}", reason)),
+            ServerToPlayerMessage::TradeOffered { offer } => {
+                self.push_log(format!("交易请求 {}", serde_json::to_string(&offer).unwrap_or_default()));
+            }
+            ServerToPlayerMessage::TradeRejected { id, by } => self.push_log(format!("交易 {} 被 {} 拒绝", id, by)),
+            ServerToPlayerMessage::PlayerStateUpdate { carried_over, refill, info } => {
+                self.push_log(format!("新回合：保留 {} 行动点，补充 {}", carried_over, refill));
+                self.me = Some(info);
+            }
+        }
+    }
+    /// 只有自己的资源和建筑会更新面板，其他玩家的变化只在原始帧里能看到
+    fn apply_diff(&mut self, diff: StateDiff) {
+        let mine = |player: &str| player == self.player_name;
+        match diff {
+            StateDiff::Phase { epoch, phase } => {
+                self.state.epoch = epoch;
+                self.state.phase = phase;
+                self.push_log(format!("第 {} 轮 {}阶段", epoch, self.phase_name()));
+            }
+            StateDiff::Players { players } => self.state.players = players,
+            StateDiff::Market { market } => self.state.market = market,
+            StateDiff::Prices { values } => self.state.values = values,
+            StateDiff::ActionPoints { player, action_points } if mine(&player) => {
+                if let Some(me) = self.me.as_mut() {
+                    me.action_points = action_points;
+                }
+            }
+            StateDiff::Buildings { player, buildings } if mine(&player) => {
+                if let Some(me) = self.me.as_mut() {
+                    me.buildings = buildings;
+                }
+            }
+            StateDiff::Resources { player, resources } if mine(&player) => {
+                if let Some(me) = self.me.as_mut() {
+                    me.resources = resources;
+                }
+            }
+            StateDiff::BankMoney { player, bank_money } if mine(&player) => {
+                if let Some(me) = self.me.as_mut() {
+                    me.bank_money = bank_money;
+                }
+            }
+            _ => {}
+        }
+    }
+    fn items(&self) -> Vec<(String, Answer)> {
+        let mut items = self.state.values.keys().copied().collect::<Vec<Items>>();
+        items.sort_by_key(|x| x.id());
+        items.into_iter().map(|x| (self.name(x.id()), Answer::Item(x))).collect()
+    }
+    fn buildings(&self, buildings: &[Building]) -> Vec<(String, Answer)> {
+        buildings.iter().map(|x| (self.name(x.id()), Answer::Building(*x))).collect()
+    }
+    /// 按当前阶段列出可以做的行动
+    fn forms(&self, catalogue: &[Building]) -> Vec<Form> {
+        let form = |title: &str, fields: Vec<(&'static str, Field)>, build: fn(&[Answer]) -> PlayerToServerMessage| Form {
+            title: title.to_string(),
+            fields,
+            answers: Vec::new(),
+            build,
+        };
+        let mut forms = Vec::new();
+        if !self.state.started {
+            forms.push(form("准备", vec![], |_| PlayerToServerMessage::Ready));
+            forms.push(form("取消准备", vec![], |_| PlayerToServerMessage::Unready));
+            forms.push(form("开始游戏", vec![], |_| PlayerToServerMessage::StartGame));
+        }
+        match self.state.phase {
+            _ if !self.state.started => {}
+            PHASE_BID => {
+                forms.push(form("出价", vec![("出多少金币", Field::Number)], |x| bid(BidAction::PlaceBid(number(&x[0])))));
+            }
+            PHASE_TAKE => {
+                let market = self.state.market.iter()
+                    .enumerate()
+                    .map(|(index, x)| (format!("{} {}", index, self.name(x.id())), Answer::Number(index as u32)))
+                    .collect();
+                forms.push(form("拿取物品", vec![("拿哪一个", Field::Choose(market))], |x| bid(BidAction::TakeItem(number(&x[0])))));
+                forms.push(form("结束拿取", vec![], |_| bid(BidAction::EndTake)));
+            }
+            PHASE_INVESTMENT => {
+                let owned = self.me.as_ref().map(|x| x.buildings.clone()).unwrap_or_default();
+                let others = self.state.players.iter()
+                    .filter(|x| **x != self.player_name)
+                    .map(|x| (x.to_string(), Answer::Player(x.to_string())))
+                    .collect();
+                let mut aims = self.buildings(catalogue)
+                    .into_iter()
+                    .map(|(label, x)| match x {
+                        Answer::Building(x) => (label, Answer::Aim(AttackTarget::Building(x))),
+                        x => (label, x),
+                    })
+                    .collect::<Vec<(String, Answer)>>();
+                aims.extend(self.items().into_iter().map(|(label, x)| match x {
+                    Answer::Item(x) => (label, Answer::Aim(AttackTarget::Stockpile(x))),
+                    x => (label, x),
+                }));
+                forms.push(form("探索", vec![], |_| invest(InvestmentAction::Explore)));
+                forms.push(form("兑换", vec![("用什么换", Field::Choose(self.items())), ("换成什么", Field::Choose(self.items())), ("数量", Field::Number)], |x| {
+                    invest(InvestmentAction::Exchange { from: item(&x[0]), to: item(&x[1]), amount: number(&x[2]) })
+                }));
+                forms.push(form("建造", vec![("建筑", Field::Choose(self.buildings(catalogue)))], |x| invest(InvestmentAction::Build(building(&x[0])))));
+                forms.push(form("升级", vec![("建筑", Field::Choose(self.buildings(&owned)))], |x| invest(InvestmentAction::Upgrade(building(&x[0])))));
+                forms.push(form("采集", vec![("物品", Field::Choose(self.items()))], |x| invest(InvestmentAction::Pick { item: item(&x[0]) })));
+                forms.push(form("挖矿", vec![], |_| invest(InvestmentAction::Ore)));
+                forms.push(form("冶炼", vec![("炼成什么", Field::Choose(self.items())), ("数量", Field::Number)], |x| {
+                    invest(InvestmentAction::Mine { into: item(&x[0]), amount: number(&x[1]) })
+                }));
+                forms.push(form("存款", vec![("金额", Field::Number)], |x| invest(InvestmentAction::Bank(BankAction::Deposit(number(&x[0]))))));
+                forms.push(form("取款", vec![("金额", Field::Number)], |x| invest(InvestmentAction::Bank(BankAction::Withdraw(number(&x[0]))))));
+                forms.push(form("攻击", vec![("目标玩家", Field::Choose(others)), ("瞄准", Field::Choose(aims))], |x| match (&x[0], &x[1]) {
+                    (Answer::Player(target), Answer::Aim(aim)) => invest(InvestmentAction::Attack { target: target.clone(), aim: *aim }),
+                    _ => unreachable!("attack form collects a player and an aim"),
+                }));
+                forms.push(form("结束投资", vec![], |_| invest(InvestmentAction::End)));
+            }
+            _ => {}
+        }
+        forms.push(form("请求快照", vec![], |_| PlayerToServerMessage::RequestSnapshot));
+        forms
+    }
+}
+fn bid(action: BidAction) -> PlayerToServerMessage {
+    PlayerToServerMessage::Bid { action }
+}
+fn invest(action: InvestmentAction) -> PlayerToServerMessage {
+    PlayerToServerMessage::Investment { action }
+}
+fn number(answer: &Answer) -> u32 {
+    match answer {
+        Answer::Number(x) => *x,
+        _ => unreachable!("field expects a number"),
+    }
+}
+fn item(answer: &Answer) -> Items {
+    match answer {
+        Answer::Item(x) => *x,
+        _ => unreachable!("field expects an item"),
+    }
+}
+fn building(answer: &Answer) -> Building {
+    match answer {
+        Answer::Building(x) => *x,
+        _ => unreachable!("field expects a building"),
+    }
+}
+fn move_selection(selected: &mut ListState, len: usize, key: KeyCode) {
+    if len == 0 {
+        return;
+    }
+    let current = selected.selected().unwrap_or(0);
+    match key {
+        KeyCode::Up => selected.select(Some((current + len - 1) % len)),
+        KeyCode::Down => selected.select(Some((current + 1) % len)),
+        _ => {}
+    }
+}
+/// 处理按键，返回需要发给服务器的消息
+fn on_key(app: &mut App, catalogue: &[Building], key: KeyEvent) -> Option<PlayerToServerMessage> {
+    match std::mem::replace(&mut app.mode, Mode::Normal) {
+        Mode::Normal => match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => app.quit = true,
+            KeyCode::Enter | KeyCode::Char('m') => {
+                app.mode = Mode::Menu { forms: app.forms(catalogue), selected: ListState::default().with_selected(Some(0)) };
+            }
+            KeyCode::Char('r') => return Some(PlayerToServerMessage::Ready),
+            KeyCode::Char('u') => return Some(PlayerToServerMessage::Unready),
+            KeyCode::Char('s') => return Some(PlayerToServerMessage::StartGame),
+            KeyCode::Char('p') => return Some(PlayerToServerMessage::RequestSnapshot),
+            KeyCode::Char('d') => {
+                app.raw_frames = !app.raw_frames;
+                app.push_log(format!("原始帧 {}", if app.raw_frames { "开" } else { "关" }));
+            }
+            _ => {}
+        },
+        Mode::Menu { mut forms, mut selected } => match key.code {
+            KeyCode::Esc => {}
+            KeyCode::Enter => {
+                let form = forms.remove(selected.selected().unwrap_or(0));
+                return next_field(app, form);
+            }
+            code => {
+                move_selection(&mut selected, forms.len(), code);
+                app.mode = Mode::Menu { forms, selected };
+            }
+        },
+        Mode::Form { mut form, mut selected, mut buffer } => {
+            let index = form.answers.len();
+            match (&form.fields[index].1, key.code) {
+                (_, KeyCode::Esc) => {}
+                (Field::Choose(options), KeyCode::Enter) => {
+                    if let Some((_, answer)) = options.get(selected.selected().unwrap_or(0)) {
+                        form.answers.push(answer.clone());
+                        return next_field(app, form);
+                    }
+                }
+                (Field::Number, KeyCode::Enter) => {
+                    if let Ok(x) = buffer.parse::<u32>() {
+                        form.answers.push(Answer::Number(x));
+                        return next_field(app, form);
+                    }
+                    app.mode = Mode::Form { form, selected, buffer };
+                }
+                (Field::Number, KeyCode::Char(x)) if x.is_ascii_digit() => {
+                    buffer.push(x);
+                    app.mode = Mode::Form { form, selected, buffer };
+                }
+                (Field::Number, KeyCode::Backspace) => {
+                    buffer.pop();
+                    app.mode = Mode::Form { form, selected, buffer };
+                }
+                (Field::Choose(options), code) => {
+                    move_selection(&mut selected, options.len(), code);
+                    app.mode = Mode::Form { form, selected, buffer };
+                }
+                _ => app.mode = Mode::Form { form, selected, buffer },
+            }
+        }
+    }
+    None
+}
+/// 表单填完就组装消息，否则进入下一项
+fn next_field(app: &mut App, form: Form) -> Option<PlayerToServerMessage> {
+    if form.answers.len() == form.fields.len() {
+        return Some((form.build)(&form.answers));
+    }
+    app.mode = Mode::Form { form, selected: ListState::default().with_selected(Some(0)), buffer: String::new() };
+    None
+}
+fn popup(area: Rect, height: u16) -> Rect {
+    let [area] = Layout::vertical([Constraint::Length(height)]).flex(Flex::Center).areas(area);
+    let [area] = Layout::horizontal([Constraint::Percentage(50)]).flex(Flex::Center).areas(area);
+    area
+}
+fn draw(frame: &mut Frame, app: &mut App) {
+    let [status, body, log, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(10),
+        Constraint::Length(12),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+    let waiting = app.state.waiting_on.join(", ");
+    let status_line = format!(
+        "{}  第 {} 轮  {}阶段  等待：{}",
+        app.player_name, app.state.epoch, app.phase_name(), waiting
+    );
+    frame.render_widget(Paragraph::new(status_line).block(Block::bordered().title("资源岛")), status);
+
+    let [market, prices, me] = Layout::horizontal([
+        Constraint::Percentage(30),
+        Constraint::Percentage(30),
+        Constraint::Percentage(40),
+    ]).areas(body);
+    let market_items = app.state.market.iter()
+        .enumerate()
+        .map(|(index, x)| format!("{} {}", index, app.name(x.id())))
+        .collect::<Vec<String>>();
+    frame.render_widget(List::new(market_items).block(Block::bordered().title("市场")), market);
+    let mut values = app.state.values.iter().collect::<Vec<(&Items, &u32)>>();
+    values.sort_by_key(|(x, _)| x.id());
+    let price_rows = values.into_iter().map(|(x, value)| Row::new(vec![app.name(x.id()), value.to_string()]));
+    frame.render_widget(
+        Table::new(price_rows, [Constraint::Percentage(60), Constraint::Percentage(40)]).block(Block::bordered().title("价格")),
+        prices,
+    );
+    let mut lines = Vec::new();
+    if let Some(info) = app.me.as_ref() {
+        lines.push(format!("行动点 {}  存款 {}  罚分 {}", info.action_points, info.bank_money, info.penalty_points));
+        let mut resources = info.resources.iter().collect::<Vec<(&Items, &u32)>>();
+        resources.sort_by_key(|(x, _)| x.id());
+        for (x, amount) in resources {
+            lines.push(format!("{} {}", app.name(x.id()), amount));
+        }
+        let mut counts = HashMap::<&Building, u32>::new();
+        for x in info.buildings.iter() {
+            *counts.entry(x).or_insert(0) += 1;
+        }
+        let buildings = counts.into_iter().map(|(x, count)| format!("{}×{}", app.name(x.id()), count)).collect::<Vec<String>>();
+        lines.push(format!("建筑 {}", buildings.join(" ")));
+        if !info.shut_down_buildings.is_empty() {
+            let shut_down = info.shut_down_buildings.iter().map(|x| app.name(x.id())).collect::<Vec<String>>();
+            lines.push(format!("停工 {}", shut_down.join(" ")));
+        }
+    }
+    frame.render_widget(Paragraph::new(lines.join("\n")).block(Block::bordered().title("我的")), me);
+
+    let shown = log.height.saturating_sub(2) as usize;
+    let log_lines = app.log.iter().skip(app.log.len().saturating_sub(shown)).cloned().collect::<Vec<String>>();
+    frame.render_widget(Paragraph::new(log_lines.join("\n")).block(Block::bordered().title("日志")), log);
+    frame.render_widget(Paragraph::new(HELP).style(Style::new().dim()), help);
+
+    let (title, options, selected) = match &mut app.mode {
+        Mode::Normal => return,
+        Mode::Menu { forms, selected } => ("行动".to_string(), forms.iter().map(|x| x.title.clone()).collect(), selected),
+        Mode::Form { form, selected, buffer } => {
+            let (label, field) = &form.fields[form.answers.len()];
+            let title = format!("{} - {}", form.title, label);
+            match field {
+                Field::Choose(options) => (title, options.iter().map(|(x, _)| x.clone()).collect(), selected),
+                Field::Number => {
+                    let area = popup(frame.area(), 3);
+                    frame.render_widget(Clear, area);
+                    frame.render_widget(Paragraph::new(format!("{}_", buffer)).block(Block::bordered().title(title)), area);
+                    return;
+                }
+            }
+        }
+    };
+    let options: Vec<String> = options;
+    let area = popup(frame.area(), options.len() as u16 + 2);
+    frame.render_widget(Clear, area);
+    let list = List::new(options)
+        .block(Block::bordered().title(title))
+        .highlight_style(Style::new().reversed())
+        .highlight_symbol("> ");
+    frame.render_stateful_widget(list, area, selected);
+}
+async fn run(terminal: &mut DefaultTerminal, mut client: GameClient) -> anyhow::Result<()> {
+    let catalogue = client.rules_summary().buildings.clone();
+    let mut app = App {
+        player_name: client.player_name().to_string(),
+        state: GameStateResponse::default(),
+        me: None,
+        log: vec![format!("已连接，会话 {}", client.session_token())],
+        raw_frames: false,
+        mode: Mode::Normal,
+        quit: false,
+    };
+    // crossterm 的读取是阻塞的，放到单独的线程里转发按键
+    let (keys_tx, mut keys) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if let Event::Key(key) = event
+                && key.kind == KeyEventKind::Press
+                && keys_tx.send(key).is_err()
+            {
+                break;
+            }
+        }
+    });
+    client.send(PlayerToServerMessage::RequestSnapshot).await?;
+    while !app.quit {
+        terminal.draw(|frame| draw(frame, &mut app))?;
+        tokio::select! {
+            msg = client.next_event() => match msg {
+                Ok(msg) => app.on_message(msg),
+                // 调试协议时正需要看到这些帧，记下来继续运行，只有连接真正断开才退出
+                Err(err @ ClientError::UndecodableFrame(..)) => app.push_log(format!("<- {}", err)),
+                Err(err) => return Err(err.into()),
+            },
+            Some(key) = keys.recv() => {
+                if let Some(msg) = on_key(&mut app, &catalogue, key) {
+                    if app.raw_frames {
+                        app.push_log(format!("-> {}", serde_json::to_string(&msg).unwrap_or_default()));
+                    }
+                    if let Err(err) = client.send(msg).await {
+                        app.push_log(format!("发送失败 {}", err));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let Some(player_name) = args.next() else {
+        eprintln!("用法：resource-island-tui <玩家名> [服务器地址] [token] [语言]");
+        return Ok(());
+    };
+    let mut options = ClientOptions::with_defaults();
+    if let Some(server) = args.next() {
+        options.server = server;
+    }
+    if let Some(token) = args.next() {
+        options.token = token;
+    }
+    if let Some(locale) = args.next().as_deref().and_then(Locale::from_code) {
+        options.locale = locale;
+    }
+    let client = GameClient::connect(player_name.as_str(), options).await?;
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, client).await;
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,212 @@
+use crate::codec;
+use crate::dtos::{GameStateResponse, PlayerInfoResponse, RulesSummary};
+use crate::enums::{self, PlayerToServerMessage, ServerToPlayerMessage};
+use crate::locale::Locale;
+use crate::PROTOCOL_VERSION;
+use futures::{SinkExt, StreamExt};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("服务器地址无效：{0}")]
+    InvalidUrl(String),
+    #[error("WebSocket 连接出错：{0}")]
+    WebSocket(#[from] Error),
+    #[error("HTTP 请求出错：{0}")]
+    Http(#[from] reqwest::Error),
+    #[error("无法解析服务器消息：{0}")]
+    Decode(#[from] anyhow::Error),
+    /// 连接仍然可用，只是这一帧不认识，调用方可以记下来继续读
+    #[error("无法解析服务器发来的帧：{1}，原始内容：{0}")]
+    UndecodableFrame(String, anyhow::Error),
+    #[error("握手失败（{0}）：{1}")]
+    Handshake(u16, String),
+    #[error("重连 {0} 次后仍然无法连接服务器")]
+    ReconnectFailed(u32),
+}
+/// 连接参数，`server` 是 HTTP 地址，WebSocket 地址由它推导
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub server: String,
+    pub token: String,
+    pub locale: Locale,
+    pub client_name: String,
+    pub reconnect_attempts: u32,
+    pub reconnect_delay: Duration,
+}
+impl ClientOptions {
+    pub fn with_defaults() -> Self {
+        Self {
+            server: "http://127.0.0.1:8080".to_string(),
+            token: "set_the_token_here".to_string(),
+            locale: Locale::default(),
+            client_name: concat!("resource-island-client/", env!("CARGO_PKG_VERSION")).to_string(),
+            reconnect_attempts: 5,
+            reconnect_delay: Duration::from_secs(1),
+        }
+    }
+}
+/// 异步客户端，负责握手、断线重连，收发的就是服务端自己的消息类型。
+/// 解析都在 `enums::with_unknown_ids` 里进行，服务端配置的自定义物品和建筑不需要在客户端注册
+pub struct GameClient {
+    player_name: String,
+    options: ClientOptions,
+    http: reqwest::Client,
+    socket: Option<Socket>,
+    /// 最近一次握手收到的 Welcome
+    welcome: ServerToPlayerMessage,
+    /// 重连成功后把新的 Welcome 交给下一次 `next_event`，调用方据此知道发生过重连
+    reconnected: Option<ServerToPlayerMessage>,
+}
+impl GameClient {
+    pub async fn connect(player_name: &str, options: ClientOptions) -> Result<GameClient, ClientError> {
+        let (socket, welcome) = handshake(player_name, &options, None).await?;
+        Ok(GameClient {
+            player_name: player_name.to_string(),
+            options,
+            http: reqwest::Client::new(),
+            socket: Some(socket),
+            welcome,
+            reconnected: None,
+        })
+    }
+    pub fn player_name(&self) -> &str {
+        self.player_name.as_str()
+    }
+    pub fn session_token(&self) -> &str {
+        match &self.welcome {
+            ServerToPlayerMessage::Welcome { session_token, .. } => session_token.as_str(),
+            _ => unreachable!("handshake only accepts welcome"),
+        }
+    }
+    pub fn rules_summary(&self) -> &RulesSummary {
+        match &self.welcome {
+            ServerToPlayerMessage::Welcome { rules_summary, .. } => rules_summary,
+            _ => unreachable!("handshake only accepts welcome"),
+        }
+    }
+    /// 发送失败时重连并重发一次
+    pub async fn send(&mut self, msg: PlayerToServerMessage) -> Result<(), ClientError> {
+        let text = serde_json::to_string(&msg).map_err(anyhow::Error::from)?;
+        if let Some(socket) = self.socket.as_mut()
+            && socket.send(Message::Text(text.clone().into())).await.is_ok()
+        {
+            return Ok(());
+        }
+        self.reconnect().await?;
+        let socket = self.socket.as_mut().expect("reconnect leaves an open socket");
+        socket.send(Message::Text(text.into())).await?;
+        Ok(())
+    }
+    /// 等待服务器的下一条消息，连接断开时自动重连。
+    /// 解析不了的帧返回 `UndecodableFrame`，之后还可以继续调用
+    pub async fn next_event(&mut self) -> Result<ServerToPlayerMessage, ClientError> {
+        loop {
+            if let Some(welcome) = self.reconnected.take() {
+                return Ok(welcome);
+            }
+            let Some(socket) = self.socket.as_mut() else {
+                self.reconnect().await?;
+                continue;
+            };
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    return enums::with_unknown_ids(|| codec::decode_text(text.as_str())).map_err(|err| ClientError::UndecodableFrame(text.to_string(), err));
+                }
+                Some(Ok(Message::Binary(bytes))) => {
+                    return enums::with_unknown_ids(|| codec::decode_binary(&bytes)).map_err(|err| {
+                        let hex = bytes.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+                        ClientError::UndecodableFrame(hex, err)
+                    });
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => self.socket = None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+    async fn reconnect(&mut self) -> Result<(), ClientError> {
+        self.socket = None;
+        for _ in 0..self.options.reconnect_attempts {
+            tokio::time::sleep(self.options.reconnect_delay).await;
+            match handshake(self.player_name.as_str(), &self.options, Some(self.session_token())).await {
+                Ok((socket, welcome)) => {
+                    self.socket = Some(socket);
+                    self.reconnected = Some(welcome.clone());
+                    self.welcome = welcome;
+                    return Ok(());
+                }
+                // 协议版本不兼容之类的错误重试也没用
+                Err(err @ ClientError::Handshake(_, _)) => return Err(err),
+                Err(_) => continue,
+            }
+        }
+        Err(ClientError::ReconnectFailed(self.options.reconnect_attempts))
+    }
+    pub async fn game_state(&self) -> Result<GameStateResponse, ClientError> {
+        self.get(&["gamestate"]).await
+    }
+    pub async fn player_info(&self) -> Result<PlayerInfoResponse, ClientError> {
+        self.get(&["playerinfo", self.player_name.as_str()]).await
+    }
+    async fn get<T: DeserializeOwned>(&self, path: &[&str]) -> Result<T, ClientError> {
+        let url = endpoint(&self.options, path)?;
+        let text = self.http.get(url).send().await?.error_for_status()?.text().await?;
+        Ok(enums::with_unknown_ids(|| codec::decode_text(text.as_str()))?)
+    }
+}
+fn endpoint(options: &ClientOptions, path: &[&str]) -> Result<Url, ClientError> {
+    let mut url = Url::parse(options.server.as_str()).map_err(|x| ClientError::InvalidUrl(x.to_string()))?;
+    url.path_segments_mut()
+        .map_err(|_| ClientError::InvalidUrl(options.server.clone()))?
+        .pop_if_empty()
+        .extend(path);
+    url.query_pairs_mut()
+        .append_pair("token", options.token.as_str())
+        .append_pair("lang", options.locale.code());
+    Ok(url)
+}
+/// 连接 `/ws/{player_name}` 并完成 Hello/Welcome 握手。
+/// 开局后重连必须带上上次 Welcome 里的会话令牌
+async fn handshake(
+    player_name: &str,
+    options: &ClientOptions,
+    session_token: Option<&str>,
+) -> Result<(Socket, ServerToPlayerMessage), ClientError> {
+    let mut url = endpoint(options, &["ws", player_name])?;
+    if let Some(session_token) = session_token {
+        url.query_pairs_mut().append_pair("session_token", session_token);
+    }
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    url.set_scheme(scheme).map_err(|_| ClientError::InvalidUrl(options.server.clone()))?;
+    let (mut socket, _) = connect_async(url.as_str()).await?;
+    let hello = PlayerToServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: options.client_name.clone(),
+        capabilities: Vec::new(),
+    };
+    let text = serde_json::to_string(&hello).map_err(anyhow::Error::from)?;
+    socket.send(Message::Text(text.into())).await?;
+    let text = loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => break text,
+            Some(Ok(Message::Close(Some(frame)))) => {
+                return Err(ClientError::Handshake(frame.code.into(), frame.reason.to_string()));
+            }
+            Some(Ok(Message::Close(None))) | None => return Err(Error::ConnectionClosed.into()),
+            Some(Err(err)) => return Err(err.into()),
+            Some(Ok(_)) => {}
+        }
+    };
+    match enums::with_unknown_ids(|| codec::decode_text(text.as_str()))? {
+        welcome @ ServerToPlayerMessage::Welcome { .. } => Ok((socket, welcome)),
+        _ => Err(ClientError::Handshake(1002, "expected welcome as the first frame".to_string())),
+    }
+}
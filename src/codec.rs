@@ -0,0 +1,48 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+pub const JSON_SUBPROTOCOL: &str = "resource-island.json";
+pub const MSGPACK_SUBPROTOCOL: &str = "resource-island.msgpack";
+
+/// 连接协商的消息编码，两种编码共用同一套消息枚举
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+/// 编码后的一帧，JSON 用文本帧发送，MessagePack 用二进制帧发送
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+impl Encoding {
+    pub fn from_subprotocol(protocol: &str) -> Option<Encoding> {
+        match protocol {
+            JSON_SUBPROTOCOL => Some(Encoding::Json),
+            MSGPACK_SUBPROTOCOL => Some(Encoding::Msgpack),
+            _ => None,
+        }
+    }
+    /// 没有使用子协议时，客户端可以在 Hello 的 capabilities 里声明 `msgpack`
+    pub fn from_capabilities(capabilities: &[String]) -> Encoding {
+        if capabilities.iter().any(|x| x == "msgpack") {
+            Encoding::Msgpack
+        } else {
+            Encoding::Json
+        }
+    }
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Frame, anyhow::Error> {
+        Ok(match self {
+            Encoding::Json => Frame::Text(serde_json::to_string(value)?),
+            // 使用带字段名的格式，和 JSON 的结构保持一致
+            Encoding::Msgpack => Frame::Binary(rmp_serde::to_vec_named(value)?),
+        })
+    }
+}
+pub fn decode_text<T: DeserializeOwned>(text: &str) -> Result<T, anyhow::Error> {
+    Ok(serde_json::from_str(text)?)
+}
+pub fn decode_binary<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, anyhow::Error> {
+    Ok(rmp_serde::from_slice(bytes)?)
+}
@@ -1,31 +1,39 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use serde::{Deserialize, Serialize};
-use tracing::{error, info, trace};
-use crate::enums::Items;
+use anyhow::Context;
+use tracing::{info, trace, warn};
+use crate::enums::{Building, Items};
+use crate::locale;
 use crate::Player;
 
 pub async fn load_configuration(file_name: &str) -> Result<GameCfg, anyhow::Error>{
     info!("YAML configurations loading...");
-    if std::path::Path::new(file_name).exists() == false{
+    if !std::path::Path::new(file_name).exists() {
         let file = File::create(file_name)?;
         serde_yaml::to_writer(file, &GameCfg::with_defaults())?;
     }
     let file = File::open(file_name)?;
-    let cfg = serde_yaml::from_reader(file);
-    if let Err(e) = &cfg {
-        error!("Failed to load YAML configurations: {}", e);
-        return Ok(GameCfg::with_defaults());
-    }
+    let mut value = serde_yaml::from_reader::<_, serde_yaml::Value>(file)?;
+    ItemCatalogue::migrate_legacy(&mut value)?;
+    BuildingCatalogue::migrate_legacy(&mut value)?;
+    ItemCatalogue::register_from_yaml(&value);
+    BuildingCatalogue::register_from_yaml(&value);
+    // 解析失败时不能退回默认配置，否则写错的建筑或物品定义会被悄悄忽略
+    let cfg = serde_yaml::from_value::<GameCfg>(value)
+        .with_context(|| format!("Failed to load YAML configurations from {}", file_name))?;
+    cfg.validate()
+        .with_context(|| format!("Invalid YAML configurations in {}", file_name))?;
+    cfg.register_names();
     trace!("loaded");
-    Ok(cfg?)
+    Ok(cfg)
 }
 pub async fn save_configuration(file_name: &str, cfg: GameCfg) -> Result<(), anyhow::Error>{
     let file = File::create(file_name)?;
     serde_yaml::to_writer(file, &cfg)?;
     Ok(())
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GameCfg {
     pub server: ServerCfg,
     pub game_rules: GameRules,
@@ -37,8 +45,89 @@ impl GameCfg {
             game_rules: GameRules::with_defaults(),
         }
     }
+    /// 把物品目录、建筑定义和探索事件中的多语言名称登记到显示名称表
+    pub fn register_names(&self) {
+        for item in self.game_rules.items.0.iter() {
+            locale::register_names(item.id.id(), &item.names);
+        }
+        for building in self.game_rules.buildings.0.iter() {
+            locale::register_names(building.id.id(), &building.names);
+        }
+        for event in self.game_rules.investment.explore.events.iter() {
+            locale::register_names(event.id.clone().leak(), &event.names);
+        }
+    }
+    /// 启动时检查物品和建筑定义，有问题直接拒绝启动，避免对局进行到一半才出错
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let rules = &self.game_rules;
+        let mut item_ids = HashSet::new();
+        for item in rules.items.0.iter() {
+            if !item_ids.insert(item.id) {
+                anyhow::bail!("item `{}` is defined more than once", item.id.id());
+            }
+        }
+        let mut building_ids = HashSet::new();
+        for building in rules.buildings.0.iter() {
+            if !building_ids.insert(building.id) {
+                anyhow::bail!("building `{}` is defined more than once", building.id.id());
+            }
+            // 显示名称按 id 查找，物品和建筑不能重名
+            if Items::from_id(building.id.id()).is_some_and(|x| item_ids.contains(&x)) {
+                anyhow::bail!("building `{}` has the same id as an item", building.id.id());
+            }
+            if building.names.is_empty() {
+                anyhow::bail!("building `{}` has no names", building.id.id());
+            }
+            for item in building.cost.keys().chain(building.production.keys()) {
+                if !item_ids.contains(item) {
+                    anyhow::bail!("building `{}` uses item `{}` which is not in the item catalogue", building.id.id(), item.id());
+                }
+            }
+        }
+        for path in rules.upgrades.paths.iter() {
+            for building in [path.from, path.to].iter().chain(path.requires.iter()) {
+                if !building_ids.contains(building) {
+                    anyhow::bail!("upgrade path uses building `{}` which is not defined", building.id());
+                }
+            }
+            if let Some(item) = path.cost.keys().find(|x| !item_ids.contains(*x)) {
+                anyhow::bail!("upgrade path uses item `{}` which is not in the item catalogue", item.id());
+            }
+        }
+        if let Some(building) = rules.ap.building_bonus.keys().find(|x| !building_ids.contains(*x)) {
+            anyhow::bail!("ap.building_bonus uses building `{}` which is not defined", building.id());
+        }
+        let production = &rules.investment.production;
+        if let Some(building) = production.farm_food_bonus.keys().find(|x| !building_ids.contains(*x)) {
+            anyhow::bail!("investment.production.farm_food_bonus uses building `{}` which is not defined", building.id());
+        }
+        if let Some(item) = production.pick.keys().chain(production.mine_ore_cost.keys()).find(|x| !item_ids.contains(*x)) {
+            anyhow::bail!("investment.production uses item `{}` which is not in the item catalogue", item.id());
+        }
+        let mut event_ids = HashSet::new();
+        for event in rules.investment.explore.events.iter() {
+            if !event_ids.insert(event.id.as_str()) {
+                anyhow::bail!("explore event `{}` is defined more than once", event.id);
+            }
+            if Items::from_id(event.id.as_str()).is_some_and(|x| item_ids.contains(&x))
+                || Building::from_id(event.id.as_str()).is_some_and(|x| building_ids.contains(&x))
+            {
+                anyhow::bail!("explore event `{}` has the same id as an item or building", event.id);
+            }
+            if let Some(item) = event.gain.keys().chain(event.lose.keys()).find(|x| !item_ids.contains(*x)) {
+                anyhow::bail!("explore event `{}` uses item `{}` which is not in the item catalogue", event.id, item.id());
+            }
+        }
+        // 出价和银行用金币，维护费用食物，挖矿和冶炼用矿石，攻击用铁，这些物品必须在目录里
+        for item in [Items::Gold, Items::Food, Items::Ore, Items::Iron] {
+            if !item_ids.contains(&item) {
+                anyhow::bail!("item `{}` is required by the game rules but missing from the item catalogue", item.id());
+            }
+        }
+        Ok(())
+    }
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerCfg {
     pub player_numbers: u32,
     pub use_token: bool,
@@ -46,6 +135,10 @@ pub struct ServerCfg {
     pub query_use_token: bool,
     pub bind_host: String,
     pub bind_port: u32,
+    #[serde(default = "SpectatorCfg::with_defaults")]
+    pub spectator: SpectatorCfg,
+    #[serde(default = "LobbyCfg::with_defaults")]
+    pub lobby: LobbyCfg,
 }
 impl ServerCfg {
     pub fn with_defaults() -> ServerCfg{
@@ -56,172 +149,657 @@ impl ServerCfg {
             query_use_token: false,
             bind_host: "0.0.0.0".into(),
             bind_port: 8080,
+            spectator: SpectatorCfg::with_defaults(),
+            lobby: LobbyCfg::with_defaults(),
         }
     }
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LobbyCfg {
+    /// 人满后是否还需要所有玩家准备才会自动开始
+    pub require_ready: bool,
+    /// 房主提前开始游戏时至少需要准备的玩家数
+    pub min_ready_players: u32,
+    /// 开始前的倒计时秒数，0 表示立即开始
+    pub countdown_secs: u32,
+}
+impl LobbyCfg {
+    pub fn with_defaults() -> LobbyCfg {
+        LobbyCfg {
+            require_ready: true,
+            min_ready_players: 2,
+            countdown_secs: 5,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpectatorCfg {
+    pub enable: bool,
+    pub max_spectators: u32,
+    /// 观战画面相对实际对局延迟的秒数，防止直播被用于作弊
+    pub delay_secs: u32,
+}
+impl SpectatorCfg {
+    pub fn with_defaults() -> SpectatorCfg {
+        SpectatorCfg {
+            enable: true,
+            max_spectators: 16,
+            delay_secs: 0,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GameRules {
     pub prepare: PrepareCfg,
-    pub resource_values_default: ResourceValuesDefault,
+    #[serde(default = "ItemCatalogue::with_defaults")]
+    pub items: ItemCatalogue,
+    #[serde(default = "BuildingCatalogue::with_defaults")]
+    pub buildings: BuildingCatalogue,
     pub investment: InvestmentCfg,
+    #[serde(default = "BidCfg::with_defaults")]
+    pub bid: BidCfg,
+    #[serde(default = "TimerCfg::with_defaults")]
+    pub timers: TimerCfg,
+    #[serde(default = "TradeCfg::with_defaults")]
+    pub trade: TradeCfg,
+    #[serde(default = "BankCfg::with_defaults")]
+    pub bank: BankCfg,
+    #[serde(default = "CombatCfg::with_defaults")]
+    pub combat: CombatCfg,
+    #[serde(default = "UpgradeCfg::with_defaults")]
+    pub upgrades: UpgradeCfg,
+    #[serde(default = "UpkeepCfg::with_defaults")]
+    pub upkeep: UpkeepCfg,
+    #[serde(default = "ApCfg::with_defaults")]
+    pub ap: ApCfg,
 }
 impl GameRules {
     pub fn with_defaults() -> GameRules {
         GameRules {
             prepare: PrepareCfg::with_defaults(),
-            resource_values_default: ResourceValuesDefault::with_defaults(),
-            investment: InvestmentCfg::with_defaults()
+            items: ItemCatalogue::with_defaults(),
+            buildings: BuildingCatalogue::with_defaults(),
+            investment: InvestmentCfg::with_defaults(),
+            bid: BidCfg::with_defaults(),
+            timers: TimerCfg::with_defaults(),
+            trade: TradeCfg::with_defaults(),
+            bank: BankCfg::with_defaults(),
+            combat: CombatCfg::with_defaults(),
+            upgrades: UpgradeCfg::with_defaults(),
+            upkeep: UpkeepCfg::with_defaults(),
+            ap: ApCfg::with_defaults(),
         }
     }
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApCfg {
+    /// 每轮结算时补充的行动点
+    pub base_refill: u32,
+    /// 未用完的行动点最多保留到下一轮的数量
+    pub carry_over_cap: u32,
+    /// 每座正在运转的建筑额外补充的行动点
+    pub building_bonus: HashMap<Building, u32>,
+    /// 本轮出价最低的玩家额外补充的行动点，所有人出价相同时不发放
+    pub last_bid_bonus: u32,
+}
+impl ApCfg {
+    pub fn with_defaults() -> ApCfg {
+        ApCfg {
+            base_refill: 5,
+            carry_over_cap: 2,
+            building_bonus: HashMap::from([(Building::Bank, 1)]),
+            last_bid_bonus: 1,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpkeepCfg {
+    pub enable: bool,
+    /// 每个玩家每轮固定消耗的食物，建筑的维护费在建筑定义中配置
+    pub flat: u32,
+    pub starvation: StarvationPenaltyCfg,
+}
+impl UpkeepCfg {
+    pub fn with_defaults() -> UpkeepCfg {
+        UpkeepCfg {
+            enable: true,
+            flat: 1,
+            starvation: StarvationPenaltyCfg::with_defaults(),
+        }
+    }
+}
+/// 食物不够支付维护费时的惩罚，可以同时配置多项
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StarvationPenaltyCfg {
+    /// 下一轮少获得的行动点
+    pub lose_ap: u32,
+    /// 下一轮停工的建筑数量，停工的建筑不产生任何效果
+    pub shut_down_buildings: u32,
+    /// 记入玩家的罚分
+    pub penalty_points: u32,
+}
+impl StarvationPenaltyCfg {
+    pub fn with_defaults() -> StarvationPenaltyCfg {
+        StarvationPenaltyCfg {
+            lose_ap: 2,
+            shut_down_buildings: 0,
+            penalty_points: 0,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpgradeCfg {
+    pub paths: Vec<UpgradePath>,
+}
+impl UpgradeCfg {
+    pub fn with_defaults() -> UpgradeCfg {
+        UpgradeCfg {
+            paths: vec![
+                UpgradePath {
+                    from: Building::Farm,
+                    to: Building::SuperFarm,
+                    ap: 2,
+                    cost: HashMap::from([(Items::Wood, 3), (Items::Gold, 2)]),
+                    requires: Vec::new(),
+                },
+                UpgradePath {
+                    from: Building::Miner,
+                    to: Building::SuperMiner,
+                    ap: 2,
+                    cost: HashMap::from([(Items::Iron, 3), (Items::Gold, 3)]),
+                    requires: Vec::new(),
+                },
+            ],
+        }
+    }
+    pub fn path_from(&self, building: Building) -> Option<&UpgradePath> {
+        self.paths.iter().find(|x| x.from == building)
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpgradePath {
+    pub from: Building,
+    pub to: Building,
+    pub ap: u32,
+    pub cost: HashMap<Items, u32>,
+    /// 升级前必须已经拥有的其他建筑
+    #[serde(default)]
+    pub requires: Vec<Building>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CombatCfg {
+    pub enable: bool,
+    pub ap_cost: u32,
+    pub iron_cost: u32,
+    /// 攻击建筑的命中率百分比
+    pub building_hit_percent: u32,
+    /// 命中建筑时摧毁的数量，目标拥有炮台时减半
+    pub building_damage: u32,
+    /// 攻击资源的命中率百分比
+    pub stockpile_hit_percent: u32,
+    /// 命中资源时摧毁的数量，目标拥有炮台时减半
+    pub stockpile_damage: u32,
+    /// 每个玩家每轮最多攻击的次数
+    pub max_attacks_per_epoch: u32,
+}
+impl CombatCfg {
+    pub fn with_defaults() -> CombatCfg {
+        CombatCfg {
+            enable: true,
+            ap_cost: 2,
+            iron_cost: 2,
+            building_hit_percent: 40,
+            building_damage: 2,
+            stockpile_hit_percent: 70,
+            stockpile_damage: 4,
+            max_attacks_per_epoch: 1,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BankCfg {
+    /// 每轮结算时存款获得的利息百分比
+    pub interest_percent: u32,
+    /// 拥有银行建筑时使用的利息百分比
+    pub bank_building_interest_percent: u32,
+    /// 存款是否不会被炮台攻击等损失波及
+    pub safe_from_losses: bool,
+}
+impl BankCfg {
+    pub fn with_defaults() -> BankCfg {
+        BankCfg {
+            interest_percent: 5,
+            bank_building_interest_percent: 15,
+            safe_from_losses: true,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeCfg {
+    pub enable: bool,
+    /// 允许玩家之间交易的阶段，阶段结束时未完成的报价会作废
+    pub phase: u32,
+}
+impl TradeCfg {
+    pub fn with_defaults() -> TradeCfg {
+        TradeCfg {
+            enable: true,
+            phase: 3,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BidCfg {
+    /// 每个玩家轮到时最多从市场拿走的物品数
+    pub take_per_turn: u32,
+}
+impl BidCfg {
+    pub fn with_defaults() -> BidCfg {
+        BidCfg {
+            take_per_turn: 2,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimerCfg {
+    pub enable: bool,
+    pub bid_secs: u32,
+    /// 拿取阶段按玩家轮流计时
+    pub take_turn_secs: u32,
+    pub investment_secs: u32,
+    /// 连续超时多少次后标记为挂机，0 表示不标记
+    pub afk_after_timeouts: u32,
+    /// 每隔多少秒向还没回应的玩家重新发送 DataRequired，0 表示不重发
+    #[serde(default = "TimerCfg::default_reprompt_secs")]
+    pub reprompt_secs: u32,
+}
+impl TimerCfg {
+    pub fn with_defaults() -> TimerCfg {
+        TimerCfg {
+            enable: true,
+            bid_secs: 60,
+            take_turn_secs: 30,
+            investment_secs: 120,
+            afk_after_timeouts: 3,
+            reprompt_secs: Self::default_reprompt_secs(),
+        }
+    }
+    /// 早期生成的配置没有这一项，按默认的 15 秒重发
+    fn default_reprompt_secs() -> u32 {
+        15
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PrepareCfg {
+    /// 固定随机种子以便复现对局，不填则每局随机
+    #[serde(default)]
+    pub seed: Option<u64>,
     pub total_epochs: u32,
     pub draw_cards: u32,
     pub defaults_give_player: DefaultsGivePlayerCfg,
-    pub deck: DeckCfg
 }
 impl PrepareCfg {
     pub fn with_defaults() -> PrepareCfg {
         PrepareCfg {
+            seed: None,
             total_epochs: 10,
             draw_cards: 10,
             defaults_give_player: DefaultsGivePlayerCfg::with_defaults(),
-            deck: DeckCfg::with_defaults(),
         }
     }
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DefaultsGivePlayerCfg {
     pub ap: u32,
-    pub diamond: u32,
-    pub gold: u32,
-    pub wood: u32,
-    pub ore: u32,
-    pub food: u32,
-    pub iron: u32,
 }
 impl DefaultsGivePlayerCfg {
     pub fn with_defaults() -> DefaultsGivePlayerCfg {
         DefaultsGivePlayerCfg {
             ap: 5,
-            diamond: 0,
-            gold: 0,
-            wood: 0,
-            ore: 0,
-            food: 5,
-            iron: 0,
         }
     }
     pub fn apply_to_player(&self, player: &mut Player) {
         player.action_points = self.ap;
-        player.resources.insert(Items::Gold, self.gold);
-        player.resources.insert(Items::Ore, self.ore);
-        player.resources.insert(Items::Diamond, self.diamond);
-        player.resources.insert(Items::Wood, self.wood);
-        player.resources.insert(Items::Iron, self.iron);
-        player.resources.insert(Items::Food, self.food);
-    }
-}
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DeckCfg {
-    pub diamond: u32,
-    pub gold: u32,
-    pub wood: u32,
-    pub ore: u32,
-    pub food: u32,
-    pub iron: u32,
-}
-impl DeckCfg {
-    pub fn with_defaults() -> DeckCfg {
-        DeckCfg {
-            diamond: 50,
-            gold: 80,
-            wood: 100,
-            ore: 100,
-            food: 100,
-            iron: 100,
-        }
-    }
-}
-impl Into<HashMap<Items, u32>> for &DeckCfg {
-    fn into(self) -> HashMap<Items, u32> {
-        let mut res = HashMap::new();
-        res.insert(Items::Diamond, self.diamond);
-        res.insert(Items::Gold, self.gold);
-        res.insert(Items::Wood, self.wood);
-        res.insert(Items::Ore, self.ore);
-        res.insert(Items::Food, self.food);
-        res.insert(Items::Iron, self.iron);
-        res
-    }
-}
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ResourceValuesDefault {
-    pub diamond: u32,
-    pub gold: u32,
-    pub wood: u32,
-    pub ore: u32,
-    pub food: u32,
-    pub iron: u32,
-}
-impl ResourceValuesDefault {
-    pub fn with_defaults() -> ResourceValuesDefault {
-        ResourceValuesDefault {
-            diamond: 8,
-            gold: 6,
-            wood: 2,
-            ore: 3,
-            food: 1,
-            iron: 2,
-        }
-    }
-}
-impl Into<HashMap<Items, u32>> for &ResourceValuesDefault {
-    fn into(self) -> HashMap<Items, u32> {
-        let mut res = HashMap::new();
-        res.insert(Items::Diamond, self.diamond);
-        res.insert(Items::Gold, self.gold);
-        res.insert(Items::Wood, self.wood);
-        res.insert(Items::Ore, self.ore);
-        res.insert(Items::Food, self.food);
-        res.insert(Items::Iron, self.iron);
-        res
-    }
-}
-#[derive(Serialize, Deserialize, Debug)]
+    }
+}
+/// 物品目录：每个物品的 id、各语言名称、初始价格、牌堆数量和初始持有量
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct ItemCatalogue(pub Vec<ItemDef>);
+impl ItemCatalogue {
+    pub fn with_defaults() -> ItemCatalogue {
+        ItemCatalogue(vec![
+            ItemDef::new(Items::Diamond, "钻石", "Diamond", 8, 50, 0),
+            ItemDef::new(Items::Gold, "金币", "Gold", 6, 80, 0),
+            ItemDef::new(Items::Wood, "木材", "Wood", 2, 100, 0),
+            ItemDef::new(Items::Ore, "矿石", "Ore", 3, 100, 0),
+            ItemDef::new(Items::Food, "食物", "Food", 1, 100, 5),
+            ItemDef::new(Items::Iron, "铁", "Iron", 2, 100, 0),
+        ])
+    }
+    /// 反序列化其余配置之前先注册目录里的自定义物品，
+    /// 这样配置的其他部分（牌堆、探索事件、升级费用等）才能引用它们
+    pub fn register_from_yaml(value: &serde_yaml::Value) {
+        let items = value
+            .get("game_rules")
+            .and_then(|x| x.get("items"))
+            .and_then(|x| x.as_sequence());
+        for item in items.into_iter().flatten() {
+            if let Some(id) = item.get("id").and_then(|x| x.as_str()) {
+                let label = item
+                    .get("names")
+                    .and_then(|x| x.get("zh"))
+                    .and_then(|x| x.as_str())
+                    .unwrap_or(id);
+                Items::register(id, label);
+            }
+        }
+    }
+    /// 早期版本把价格、牌堆数量和初始持有量分别写在 `resource_values_default`、`prepare.deck`
+    /// 和 `prepare.defaults_give_player` 的内置物品字段里。这里把旧字段合并进物品目录，
+    /// 同时写了新旧两种格式时拒绝启动，免得其中一边被悄悄忽略
+    pub fn migrate_legacy(value: &mut serde_yaml::Value) -> Result<(), anyhow::Error> {
+        let Some(rules) = value.get_mut("game_rules").and_then(|x| x.as_mapping_mut()) else {
+            return Ok(());
+        };
+        let values = rules.remove("resource_values_default");
+        let mut deck = None;
+        let mut starting = serde_yaml::Mapping::new();
+        if let Some(prepare) = rules.get_mut("prepare").and_then(|x| x.as_mapping_mut()) {
+            deck = prepare.remove("deck");
+            if let Some(give) = prepare.get_mut("defaults_give_player").and_then(|x| x.as_mapping_mut()) {
+                for item in Items::BUILTIN.iter() {
+                    if let Some(amount) = give.remove(item.id()) {
+                        starting.insert(item.id().into(), amount);
+                    }
+                }
+            }
+        }
+        if values.is_none() && deck.is_none() && starting.is_empty() {
+            return Ok(());
+        }
+        if rules.contains_key("items") {
+            anyhow::bail!(
+                "game_rules.items cannot be combined with the old resource_values_default, prepare.deck \
+                 or per-item prepare.defaults_give_player keys; move those values into game_rules.items"
+            );
+        }
+        let starting = serde_yaml::Value::Mapping(starting);
+        let legacy = |fields: Option<&serde_yaml::Value>, item: Items| {
+            fields.and_then(|x| x.get(item.id())).and_then(|x| x.as_u64()).map(|x| x as u32)
+        };
+        let mut catalogue = ItemCatalogue::with_defaults();
+        for item in catalogue.0.iter_mut() {
+            item.value = legacy(values.as_ref(), item.id).unwrap_or(item.value);
+            item.deck = legacy(deck.as_ref(), item.id).unwrap_or(item.deck);
+            item.starting = legacy(Some(&starting), item.id).unwrap_or(item.starting);
+        }
+        warn!("Config uses the old per-item price, deck and starting keys; they were migrated into game_rules.items");
+        rules.insert("items".into(), serde_yaml::to_value(&catalogue)?);
+        Ok(())
+    }
+    pub fn values(&self) -> HashMap<Items, u32> {
+        self.0.iter().map(|x| (x.id, x.value)).collect()
+    }
+    pub fn deck(&self) -> HashMap<Items, u32> {
+        self.0.iter().map(|x| (x.id, x.deck)).collect()
+    }
+    pub fn apply_to_player(&self, player: &mut Player) {
+        for item in self.0.iter() {
+            player.resources.insert(item.id, item.starting);
+        }
+    }
+}
+/// 建筑定义：建造费用、行动点、维护费、每轮产出和数量上限
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct BuildingCatalogue(pub Vec<BuildingDef>);
+impl BuildingCatalogue {
+    pub fn with_defaults() -> BuildingCatalogue {
+        BuildingCatalogue(vec![
+            BuildingDef::new(Building::Farm, "农场", "Farm", true, &[(Items::Wood, 3)], &[(Items::Food, 1)]),
+            BuildingDef::new(Building::SuperFarm, "无敌农场", "Super Farm", false, &[], &[(Items::Food, 2)]),
+            BuildingDef::new(Building::Miner, "矿机", "Miner", true, &[(Items::Wood, 2), (Items::Iron, 2)], &[(Items::Ore, 1)]),
+            BuildingDef::new(Building::SuperMiner, "高级矿机", "Super Miner", false, &[], &[(Items::Ore, 2)]),
+            BuildingDef::new(Building::Bank, "银行", "Bank", true, &[(Items::Wood, 2), (Items::Gold, 4)], &[]),
+            BuildingDef::new(Building::Cannon, "炮台", "Cannon", true, &[(Items::Iron, 4)], &[]),
+        ])
+    }
+    pub fn register_from_yaml(value: &serde_yaml::Value) {
+        let buildings = value
+            .get("game_rules")
+            .and_then(|x| x.get("buildings"))
+            .and_then(|x| x.as_sequence());
+        for building in buildings.into_iter().flatten() {
+            if let Some(id) = building.get("id").and_then(|x| x.as_str()) {
+                let label = building
+                    .get("names")
+                    .and_then(|x| x.get("zh"))
+                    .and_then(|x| x.as_str())
+                    .unwrap_or(id);
+                Building::register(id, label);
+            }
+        }
+    }
+    /// 早期版本所有建筑共用 `investment.needs_ap.build`，迁移时把它写进每个建筑定义；
+    /// 已经有建筑定义时拒绝启动
+    pub fn migrate_legacy(value: &mut serde_yaml::Value) -> Result<(), anyhow::Error> {
+        let Some(rules) = value.get_mut("game_rules").and_then(|x| x.as_mapping_mut()) else {
+            return Ok(());
+        };
+        let ap = rules
+            .get_mut("investment")
+            .and_then(|x| x.get_mut("needs_ap"))
+            .and_then(|x| x.as_mapping_mut())
+            .and_then(|x| x.remove("build"));
+        let Some(ap) = ap else {
+            return Ok(());
+        };
+        if rules.contains_key("buildings") {
+            anyhow::bail!(
+                "game_rules.buildings cannot be combined with the old investment.needs_ap.build key; \
+                 set ap on each building instead"
+            );
+        }
+        let mut catalogue = BuildingCatalogue::with_defaults();
+        for building in catalogue.0.iter_mut() {
+            if let Some(ap) = ap.as_u64() {
+                building.ap = ap as u32;
+            }
+        }
+        warn!("Config uses the old shared build AP key; it was migrated into game_rules.buildings");
+        rules.insert("buildings".into(), serde_yaml::to_value(&catalogue)?);
+        Ok(())
+    }
+    pub fn get(&self, building: Building) -> Option<&BuildingDef> {
+        self.0.iter().find(|x| x.id == building)
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildingDef {
+    pub id: Building,
+    pub names: HashMap<String, String>,
+    /// 只能通过升级获得的建筑不能直接建造
+    pub buildable: bool,
+    pub cost: HashMap<Items, u32>,
+    pub ap: u32,
+    /// 每轮结算时消耗的食物
+    pub upkeep: u32,
+    /// 每轮结算时产出的资源
+    pub production: HashMap<Items, u32>,
+    /// 每个玩家最多拥有的数量，0 表示不限制
+    pub max_per_player: u32,
+}
+impl BuildingDef {
+    pub fn new(
+        id: Building,
+        zh: &str,
+        en: &str,
+        buildable: bool,
+        cost: &[(Items, u32)],
+        production: &[(Items, u32)],
+    ) -> BuildingDef {
+        BuildingDef {
+            id,
+            names: HashMap::from([("zh".to_string(), zh.to_string()), ("en".to_string(), en.to_string())]),
+            buildable,
+            cost: cost.iter().cloned().collect(),
+            ap: 3,
+            upkeep: 1,
+            production: production.iter().cloned().collect(),
+            max_per_player: 0,
+        }
+    }
+}#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemDef {
+    pub id: Items,
+    /// 语言代码到显示名称，例如 zh、en
+    pub names: HashMap<String, String>,
+    pub value: u32,
+    pub deck: u32,
+    pub starting: u32,
+}
+impl ItemDef {
+    pub fn new(id: Items, zh: &str, en: &str, value: u32, deck: u32, starting: u32) -> ItemDef {
+        ItemDef {
+            id,
+            names: HashMap::from([("zh".to_string(), zh.to_string()), ("en".to_string(), en.to_string())]),
+            value,
+            deck,
+            starting,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InvestmentCfg {
     pub enable: bool,
     pub needs_ap: InvestmentApCosts,
+    #[serde(default = "ExchangeCfg::with_defaults")]
+    pub exchange: ExchangeCfg,
+    #[serde(default = "ExploreCfg::with_defaults")]
+    pub explore: ExploreCfg,
+    #[serde(default = "ProductionCfg::with_defaults")]
+    pub production: ProductionCfg,
 }
 impl InvestmentCfg {
     pub fn with_defaults() -> InvestmentCfg {
         InvestmentCfg {
             enable: true,
             needs_ap: InvestmentApCosts::with_defaults(),
+            exchange: ExchangeCfg::with_defaults(),
+            explore: ExploreCfg::with_defaults(),
+            production: ProductionCfg::with_defaults(),
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExchangeCfg {
+    /// 按当前价格兑换时扣除的手续费百分比
+    pub fee_percent: u32,
+    /// 每个玩家每轮最多兑换出去的资源数量，0 表示不限制
+    pub max_per_epoch: u32,
+}
+impl ExchangeCfg {
+    pub fn with_defaults() -> ExchangeCfg {
+        ExchangeCfg {
+            fee_percent: 10,
+            max_per_epoch: 10,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExploreCfg {
+    /// 探索时按权重从中抽取一个事件
+    pub events: Vec<ExploreEvent>,
+}
+impl ExploreCfg {
+    pub fn with_defaults() -> ExploreCfg {
+        ExploreCfg {
+            events: vec![
+                ExploreEvent::new("found_wood", "找到2个木材", "Found 2 wood", 30, &[(Items::Wood, 2)], &[]),
+                ExploreEvent::new("found_ore", "找到2个矿石", "Found 2 ore", 20, &[(Items::Ore, 2)], &[]),
+                ExploreEvent::new("storm", "风暴中损失1个食物", "Lost 1 food in a storm", 20, &[], &[(Items::Food, 1)]),
+                ExploreEvent::new("diamond_vein", "发现钻石矿脉", "Discovered a diamond vein", 5, &[(Items::Diamond, 1)], &[]),
+                ExploreEvent::new("nothing", "一无所获", "Found nothing", 25, &[], &[]),
+            ],
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExploreEvent {
+    /// 探索结果里只带 id，显示名称和物品、建筑一样按语言从 `names` 查
+    pub id: String,
+    pub names: HashMap<String, String>,
+    pub weight: u32,
+    #[serde(default)]
+    pub gain: HashMap<Items, u32>,
+    /// 玩家不够扣时只扣到 0
+    #[serde(default)]
+    pub lose: HashMap<Items, u32>,
+}
+impl ExploreEvent {
+    pub fn new(id: &str, zh: &str, en: &str, weight: u32, gain: &[(Items, u32)], lose: &[(Items, u32)]) -> ExploreEvent {
+        ExploreEvent {
+            id: id.to_string(),
+            names: HashMap::from([("zh".to_string(), zh.to_string()), ("en".to_string(), en.to_string())]),
+            weight,
+            gain: gain.iter().cloned().collect(),
+            lose: lose.iter().cloned().collect(),
         }
     }
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProductionCfg {
+    /// 徒手采集（Pick）一次得到的数量，只有列出的资源可以采集
+    pub pick: HashMap<Items, u32>,
+    /// 每座建筑让采集食物多得的数量
+    pub farm_food_bonus: HashMap<Building, u32>,
+    /// 挖矿（Ore）一次得到的矿石数量
+    pub ore: u32,
+    /// 拥有矿机时挖矿额外得到的矿石数量
+    pub miner_ore_bonus: u32,
+    /// 冶炼（Mine）每得到 1 个产物需要消耗的矿石，需要拥有矿机
+    pub mine_ore_cost: HashMap<Items, u32>,
+    /// 拥有高级矿机时冶炼产量的倍数
+    pub super_miner_multiplier: u32,
+}
+impl ProductionCfg {
+    pub fn with_defaults() -> ProductionCfg {
+        ProductionCfg {
+            pick: HashMap::from([(Items::Food, 2), (Items::Wood, 1)]),
+            farm_food_bonus: HashMap::from([(Building::Farm, 1), (Building::SuperFarm, 2)]),
+            ore: 1,
+            miner_ore_bonus: 1,
+            mine_ore_cost: HashMap::from([(Items::Iron, 1), (Items::Gold, 2)]),
+            super_miner_multiplier: 2,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InvestmentApCosts {
     pub explore: u32,
     pub exchange: u32,
-    pub build: u32,
     pub open: u32,
     pub bank: u32,
     pub mine: u32,
     pub pick: u32,
+    #[serde(default = "InvestmentApCosts::default_ore")]
+    pub ore: u32,
 }
 impl InvestmentApCosts {
     pub fn with_defaults() -> InvestmentApCosts {
         InvestmentApCosts {
             explore: 1,
             exchange: 2,
-            build: 3,
             open: 1,
             bank: 0,
-            mine: 0,
-            pick: 0,
+            mine: 1,
+            pick: 1,
+            ore: Self::default_ore(),
         }
     }
-}
\ No newline at end of file
+    /// 旧配置文件里没有这一项时和默认配置保持一致
+    fn default_ore() -> u32 {
+        1
+    }
+}
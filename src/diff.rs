@@ -0,0 +1,142 @@
+use crate::enums::{Building, Items, ServerToPlayerMessage, StateDiff};
+use crate::{GameState, Player};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq)]
+struct PlayerView {
+    action_points: u32,
+    buildings: Vec<Building>,
+    resources: HashMap<Items, u32>,
+    bank_money: u32,
+}
+impl From<&Player> for PlayerView {
+    fn from(value: &Player) -> Self {
+        Self {
+            action_points: value.action_points,
+            buildings: value.buildings.clone(),
+            resources: value.resources.clone(),
+            bank_money: value.bank_money,
+        }
+    }
+}
+/// 上一次推送给客户端的状态，用来计算下一次的增量
+#[derive(Clone, PartialEq, Default)]
+pub struct PublishedState {
+    epoch: u32,
+    phase: u32,
+    players: Vec<&'static str>,
+    market: Vec<Items>,
+    values: HashMap<Items, u32>,
+    player_views: HashMap<&'static str, PlayerView>,
+}
+impl From<&GameState> for PublishedState {
+    fn from(value: &GameState) -> Self {
+        let mut players = value.players.keys().cloned().collect::<Vec<&'static str>>();
+        players.sort();
+        Self {
+            epoch: value.epoch,
+            phase: value.phase,
+            players,
+            market: value.market.clone(),
+            values: value.resource_values.clone(),
+            player_views: value
+                .players
+                .iter()
+                .map(|(name, player)| (*name, PlayerView::from(player)))
+                .collect(),
+        }
+    }
+}
+impl PublishedState {
+    /// 所有增量都携带变化后的完整值，重复应用不会出错
+    pub fn diff(&self, new: &PublishedState) -> Vec<StateDiff> {
+        let mut res = Vec::new();
+        if self.epoch != new.epoch || self.phase != new.phase {
+            res.push(StateDiff::Phase {
+                epoch: new.epoch,
+                phase: new.phase,
+            });
+        }
+        if self.players != new.players {
+            res.push(StateDiff::Players {
+                players: new.players.iter().map(|x| Cow::Borrowed(*x)).collect(),
+            });
+        }
+        if self.market != new.market {
+            res.push(StateDiff::Market {
+                market: new.market.clone(),
+            });
+        }
+        if self.values != new.values {
+            res.push(StateDiff::Prices {
+                values: new.values.clone(),
+            });
+        }
+        for (&player, view) in new.player_views.iter() {
+            let old = self.player_views.get(player);
+            if old.map(|x| x.action_points) != Some(view.action_points) {
+                res.push(StateDiff::ActionPoints {
+                    player: player.into(),
+                    action_points: view.action_points,
+                });
+            }
+            if old.map(|x| &x.buildings) != Some(&view.buildings) {
+                res.push(StateDiff::Buildings {
+                    player: player.into(),
+                    buildings: view.buildings.clone(),
+                });
+            }
+            if old.map(|x| &x.resources) != Some(&view.resources) {
+                res.push(StateDiff::Resources {
+                    player: player.into(),
+                    resources: view.resources.clone(),
+                });
+            }
+            if old.map(|x| x.bank_money) != Some(view.bank_money) {
+                res.push(StateDiff::BankMoney {
+                    player: player.into(),
+                    bank_money: view.bank_money,
+                });
+            }
+        }
+        res
+    }
+}
+impl GameState {
+    /// 比较当前状态和上一次推送的状态，有变化时递增版本号并推送增量
+    pub async fn publish_diffs(&mut self) {
+        let current = PublishedState::from(&*self);
+        let diffs = self.published.diff(&current);
+        self.published = current;
+        if diffs.is_empty() {
+            return;
+        }
+        self.version += 1;
+        for (&name, player) in self.players.iter() {
+            if !player.connected {
+                continue;
+            }
+            let visible = diffs
+                .iter()
+                .filter(|x| x.private_to().is_none_or(|owner| owner == name))
+                .cloned()
+                .collect::<Vec<StateDiff>>();
+            player.push(name, ServerToPlayerMessage::StateDiff {
+                version: self.version,
+                diffs: visible,
+            });
+        }
+        let public = diffs
+            .into_iter()
+            .filter(|x| x.private_to().is_none())
+            .collect::<Vec<StateDiff>>();
+        if !public.is_empty() {
+            self.send_spectators(ServerToPlayerMessage::StateDiff {
+                version: self.version,
+                diffs: public,
+            })
+            .await;
+        }
+    }
+}
@@ -1,37 +1,48 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tracing::trace;
+use crate::config::GameCfg;
+use crate::enums::{BankRecord, Building, Items};
+use crate::locale::{self, Locale};
+use crate::trade::TradeOffer;
 use crate::{GameState, Player};
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct GameStateResponse {
-    pub players: Vec<&'static str>,
-    pub market: Vec<&'static str>,
+    pub players: Vec<Cow<'static, str>>,
+    pub market: Vec<Items>,
     pub epoch: u32,
     pub phase: u32,
-    pub values: HashMap<&'static str, u32>,
-    pub started: bool
+    pub values: HashMap<Items, u32>,
+    pub started: bool,
+    pub waiting_on: Vec<Cow<'static, str>>,
+    /// id 到显示名称，按请求的语言填充
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub names: HashMap<Cow<'static, str>, Cow<'static, str>>,
 }
 impl From<&GameState> for GameStateResponse {
     fn from(value: &GameState) -> Self {
         trace!("{:?}", value.market);
-        let market = value.market.iter()
-            .map(|x| x.into()).collect::<Vec<&'static str>>();
+        let market = value.market.clone();
         let epoch = value.epoch;
         let phase = value.phase;
         let started = value.started;
-        let values = value.resource_values.iter()
-            .map(|(item, &v)| (item.into(), v)).collect::<HashMap<&'static str, u32>>();
+        let waiting_on = value.waiting_on.iter().map(|x| Cow::Borrowed(*x)).collect();
+        let values = value.resource_values.clone();
         let players = value.players.keys()
-            .cloned()
-            .collect::<Vec<&'static str>>();
+            .map(|x| Cow::Borrowed(*x))
+            .collect::<Vec<Cow<'static, str>>>();
         Self {
             players,
             market,
             epoch,
             phase,
             values,
-            started
+            started,
+            waiting_on,
+            names: HashMap::new(),
         }
     }
 }
@@ -39,43 +50,44 @@ impl GameStateResponse {
     pub fn with_error() -> GameStateResponse {
         Default::default()
     }
-}
-impl Default for GameStateResponse {
-    fn default() -> Self {
-        GameStateResponse {
-            players: Vec::new(),
-            market: Vec::new(),
-            epoch: 0,
-            phase: 0,
-            values: HashMap::new(),
-            started: false
-        }
+    pub fn localized(mut self, locale: Locale) -> GameStateResponse {
+        self.names = locale::display_names(locale)
+            .into_iter()
+            .map(|(x, y)| (Cow::Borrowed(x), Cow::Borrowed(y)))
+            .collect();
+        self
     }
 }
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct PlayerInfoResponse {
-    action_points: u32,
-    resources: HashMap<&'static str, u32>,
-    buildings: Vec<&'static str>,
-    bank_money: u32,
+    pub action_points: u32,
+    pub resources: HashMap<Items, u32>,
+    pub buildings: Vec<Building>,
+    pub bank_money: u32,
+    pub bank_history: Vec<BankRecord>,
+    pub shut_down_buildings: Vec<Building>,
+    pub penalty_points: u32,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub names: HashMap<Cow<'static, str>, Cow<'static, str>>,
 }
 impl From<&Player> for PlayerInfoResponse {
     fn from(value: &Player) -> Self {
         let action_points = value.action_points;
-        let resources = value.resources.iter()
-            .map(|(x, y)| {
-                let item_str: &'static str = x.into();
-                (item_str, *y)
-            }).collect::<HashMap<&'static str, u32>>();
-        let buildings = value.buildings.iter()
-            .map(|x| x.into())
-            .collect::<Vec<&'static str>>();
+        let resources = value.resources.clone();
+        let buildings = value.buildings.clone();
         let bank_money = value.bank_money;
+        let bank_history = value.bank_history.clone();
+        let shut_down_buildings = value.shut_down_buildings.clone();
+        let penalty_points = value.penalty_points;
         Self {
             action_points,
             resources,
             buildings,
-            bank_money
+            bank_money,
+            bank_history,
+            shut_down_buildings,
+            penalty_points,
+            names: HashMap::new(),
         }
     }
 }
@@ -83,14 +95,59 @@ impl PlayerInfoResponse {
     pub fn with_error() -> PlayerInfoResponse {
         Default::default()
     }
+    pub fn localized(mut self, locale: Locale) -> PlayerInfoResponse {
+        self.names = locale::display_names(locale)
+            .into_iter()
+            .map(|(x, y)| (Cow::Borrowed(x), Cow::Borrowed(y)))
+            .collect();
+        self
+    }
 }
-impl Default for PlayerInfoResponse {
-    fn default() -> Self {
-        PlayerInfoResponse {
-            action_points: 0,
-            resources: HashMap::new(),
-            buildings: Vec::new(),
-            bank_money: 0
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct TradeOfferResponse {
+    pub id: u32,
+    pub from: Cow<'static, str>,
+    pub to: Cow<'static, str>,
+    pub give: HashMap<Items, u32>,
+    pub want: HashMap<Items, u32>,
+}
+impl From<&TradeOffer> for TradeOfferResponse {
+    fn from(value: &TradeOffer) -> Self {
+        let give = value.give.clone();
+        let want = value.want.clone();
+        Self {
+            id: value.id,
+            from: value.from.into(),
+            to: value.to.into(),
+            give,
+            want
         }
     }
-}
\ No newline at end of file
+}
+/// 握手时告诉客户端本局的主要规则，客户端据此决定显示哪些功能
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RulesSummary {
+    pub player_numbers: u32,
+    pub total_epochs: u32,
+    pub items: Vec<Items>,
+    pub buildings: Vec<Building>,
+    pub investment: bool,
+    pub trade: bool,
+    pub combat: bool,
+    pub timers: bool,
+}
+impl From<&GameCfg> for RulesSummary {
+    fn from(value: &GameCfg) -> Self {
+        let rules = &value.game_rules;
+        Self {
+            player_numbers: value.server.player_numbers,
+            total_epochs: rules.prepare.total_epochs,
+            items: rules.items.0.iter().map(|x| x.id).collect(),
+            buildings: rules.buildings.0.iter().map(|x| x.id).collect(),
+            investment: rules.investment.enable,
+            trade: rules.trade.enable,
+            combat: rules.combat.enable,
+            timers: rules.timers.enable,
+        }
+    }
+}
@@ -1,103 +1,260 @@
 use crate::NoSuchFound;
-use serde::Serialize;
+use crate::locale::{self, Locale};
+use crate::dtos::{GameStateResponse, PlayerInfoResponse, RulesSummary, TradeOfferResponse};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::convert::TryFrom;
+use std::sync::RwLock;
 
-#[derive(Eq, Hash, PartialEq, Copy, Clone, Debug)]
-pub enum Items {
-    Gold,
-    Wood,
-    Diamond,
-    Ore,
-    Food,
-    Iron,
+thread_local! {
+    static LEGACY_LABELS: Cell<bool> = const { Cell::new(false) };
+    static UNKNOWN_IDS: Cell<bool> = const { Cell::new(false) };
 }
-impl TryFrom<&'static str> for Items {
-    type Error = NoSuchFound;
-
-    fn try_from(value: &'static str) -> Result<Self, Self::Error> {
-        if value == "金币" {
-            Ok(Self::Gold)
-        } else if value == "钻石" {
-            Ok(Self::Diamond)
-        } else if value == "木材" {
-            Ok(Self::Wood)
-        } else if value == "矿石" {
-            Ok(Self::Ore)
-        } else if value == "铁" {
-            Ok(Self::Iron)
-        } else if value == "食物" {
-            Ok(Self::Food)
-        } else {
-            Err(NoSuchFound::NoSuchItems(value))
-        }
-    }
+/// 兼容模式：在 `f` 内序列化的物品和建筑输出旧版中文名称而不是 id，
+/// 供还在按中文匹配的老客户端使用
+pub fn with_legacy_labels<T>(enable: bool, f: impl FnOnce() -> T) -> T {
+    let old = LEGACY_LABELS.replace(enable);
+    let res = f();
+    LEGACY_LABELS.set(old);
+    res
 }
-impl Into<&'static str> for &Items {
-    fn into(self) -> &'static str {
-        match self {
-            Items::Gold => "金币",
-            Items::Wood => "木材",
-            Items::Diamond => "钻石",
-            Items::Ore => "矿石",
-            Items::Food => "食物",
-            Items::Iron => "铁",
+/// 客户端模式：在 `f` 内反序列化时接受没有注册过的物品和建筑 id，解析为 `Custom`。
+/// 这些 id 记在单独的表里，不会进入配置的注册表，服务端的 `from_id` 仍然不认识它们
+pub fn with_unknown_ids<T>(f: impl FnOnce() -> T) -> T {
+    let old = UNKNOWN_IDS.replace(true);
+    let res = f();
+    UNKNOWN_IDS.set(old);
+    res
+}
+/// 配置文件注册的自定义物品或建筑：(id, 中文名)
+pub struct Registry(RwLock<Vec<(&'static str, &'static str)>>);
+impl Registry {
+    const fn new() -> Registry {
+        Registry(RwLock::new(Vec::new()))
+    }
+    fn by_id(&self, id: &str) -> Option<&'static str> {
+        self.0.read().unwrap().iter().find(|(x, _)| *x == id).map(|(x, _)| *x)
+    }
+    fn by_label(&self, label: &str) -> Option<&'static str> {
+        self.0.read().unwrap().iter().find(|(_, x)| *x == label).map(|(x, _)| *x)
+    }
+    fn label(&self, id: &'static str) -> &'static str {
+        self.0.read().unwrap().iter().find(|(x, _)| *x == id).map(|(_, x)| *x).unwrap_or(id)
+    }
+    /// 同一个 id 只会注册一次
+    fn register(&self, id: &str, label: &str) -> &'static str {
+        let mut custom = self.0.write().unwrap();
+        if let Some((x, _)) = custom.iter().find(|(x, _)| *x == id) {
+            return x;
         }
+        let id: &'static str = id.to_string().leak();
+        let label: &'static str = label.to_string().leak();
+        custom.push((id, label));
+        id
     }
 }
-#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
-pub enum Building {
-    Farm,
-    SuperFarm,
-    Miner,
-    SuperMiner,
-    Bank,
-    Cannon,
-}
-impl TryFrom<&'static str> for Building {
-    type Error = NoSuchFound;
+/// 物品和建筑共用的 id、中文名、注册表和序列化实现。
+/// 协议里二者都是字符串：正常情况下是 id，兼容模式下是中文名称，解析时两种都接受
+macro_rules! catalogue_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident in $registry:ident {
+            $($variant:ident => ($id:literal, $label:literal),)*
+        }
+        kind: ($kind:literal, $kind_en:literal),
+        not_found: $not_found:path,
+        fuzzy: $fuzzy:expr,
+    ) => {
+        $(#[$meta])*
+        #[derive(Eq, Hash, PartialEq, Copy, Clone, Debug)]
+        pub enum $name {
+            $($variant,)*
+            Custom(&'static str),
+        }
+        static $registry: Registry = Registry::new();
+        impl $name {
+            pub const BUILTIN: &'static [$name] = &[$($name::$variant,)*];
 
-    fn try_from(value: &'static str) -> Result<Self, Self::Error> {
-        if value == "农场" {
-            Ok(Self::Farm)
-        } else if value.contains("农场") && value != "农场" {
-            Ok(Self::SuperFarm)
-        } else if value == "矿机" {
-            Ok(Self::Miner)
-        } else if value.contains("矿机") && value != "矿机" {
-            Ok(Self::SuperMiner)
-        } else if value == "银行" {
-            Ok(Self::Bank)
-        } else if value == "炮台" {
-            Ok(Self::Cannon)
-        } else {
-            Err(NoSuchFound::NoSuchBuildings(value))
+            pub fn id(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $id,)*
+                    $name::Custom(id) => id,
+                }
+            }
+            pub fn from_id(id: &str) -> Option<$name> {
+                if let Some(x) = Self::BUILTIN.iter().find(|x| x.id() == id) {
+                    return Some(*x);
+                }
+                $registry.by_id(id).map($name::Custom)
+            }
+            #[doc = concat!("注册配置文件中声明的", $kind, "，同一个 id 只会注册一次")]
+            pub fn register(id: &str, label: &str) -> $name {
+                Self::from_id(id).unwrap_or_else(|| $name::Custom($registry.register(id, label)))
+            }
+            /// 兼容模式下客户端发来的是中文名称
+            pub fn from_label(label: &str) -> Option<$name> {
+                let fuzzy: fn(&str) -> Option<$name> = $fuzzy;
+                $registry
+                    .by_label(label)
+                    .map($name::Custom)
+                    .or_else(|| Self::BUILTIN.iter().copied().find(|x| <&'static str>::from(x) == label))
+                    .or_else(|| fuzzy(label))
+            }
+            /// 优先使用配置中的名称，没有配置时中文退回内置名称，其它语言退回 id
+            pub fn display_name(&self, locale: Locale) -> &'static str {
+                locale::display_name(self.id(), locale).unwrap_or_else(|| match locale {
+                    Locale::Zh => self.into(),
+                    _ => self.id(),
+                })
+            }
+        }
+        impl TryFrom<&'static str> for $name {
+            type Error = NoSuchFound;
+
+            fn try_from(value: &'static str) -> Result<Self, Self::Error> {
+                $name::from_label(value).ok_or($not_found(value))
+            }
         }
+        impl From<&$name> for &'static str {
+            fn from(value: &$name) -> Self {
+                match value {
+                    $($name::$variant => $label,)*
+                    $name::Custom(id) => $registry.label(id),
+                }
+            }
+        }
+        impl Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                if LEGACY_LABELS.get() {
+                    serializer.serialize_str(self.into())
+                } else {
+                    serializer.serialize_str(self.id())
+                }
+            }
+        }
+        /// 自定义 id 来自配置，所以不列出取值
+        impl JsonSchema for $name {
+            fn schema_name() -> Cow<'static, str> {
+                stringify!($name).into()
+            }
+            fn json_schema(_: &mut SchemaGenerator) -> Schema {
+                json_schema!({
+                    "type": "string",
+                    "description": concat!($kind, " id，例如 ", $($id, "、",)* "自定义 id；兼容模式下为中文名称"),
+                    "examples": Self::BUILTIN.iter().map(|x| x.id()).collect::<Vec<&'static str>>(),
+                })
+            }
+        }
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                // 客户端收到的自定义 id 来自服务端的配置，同一个 id 只记一次
+                static UNKNOWN: Registry = Registry::new();
+                let id = String::deserialize(deserializer)?;
+                $name::from_id(id.as_str())
+                    .or_else(|| $name::from_label(id.as_str()))
+                    .or_else(|| UNKNOWN_IDS.get().then(|| $name::Custom(UNKNOWN.register(id.as_str(), id.as_str()))))
+                    .ok_or_else(|| serde::de::Error::custom(format!(concat!("unknown ", $kind_en, " `{}`"), id)))
+            }
+        }
+    };
+}
+catalogue_enum! {
+    /// 内置资源有固定的游戏规则含义（金币用于出价、食物用于维护费等），
+    /// 其余资源由配置文件的物品目录定义，注册后以 `Custom` 表示
+    pub enum Items in CUSTOM_ITEMS {
+        Gold => ("gold", "金币"),
+        Wood => ("wood", "木材"),
+        Diamond => ("diamond", "钻石"),
+        Ore => ("ore", "矿石"),
+        Food => ("food", "食物"),
+        Iron => ("iron", "铁"),
     }
+    kind: ("物品", "item"),
+    not_found: NoSuchFound::NoSuchItems,
+    fuzzy: |_| None,
 }
-impl Into<&'static str> for &Building {
-    fn into(self) -> &'static str {
-        match self {
-            Building::Farm => "农场",
-            Building::SuperFarm => "无敌农场",
-            Building::Miner => "矿机",
-            Building::SuperMiner => "高级矿机",
-            Building::Bank => "银行",
-            Building::Cannon => "炮台",
-        }
+catalogue_enum! {
+    /// 内置建筑带有特殊规则（银行利息、炮台攻击、矿机冶炼等），
+    /// 其余建筑由配置文件的建筑定义声明，注册后以 `Custom` 表示
+    pub enum Building in CUSTOM_BUILDINGS {
+        Farm => ("farm", "农场"),
+        SuperFarm => ("super_farm", "无敌农场"),
+        Miner => ("miner", "矿机"),
+        SuperMiner => ("super_miner", "高级矿机"),
+        Bank => ("bank", "银行"),
+        Cannon => ("cannon", "炮台"),
     }
+    kind: ("建筑", "building"),
+    not_found: NoSuchFound::NoSuchBuildings,
+    // 老版本把“超级农场”之类的写法都当作升级后的建筑
+    fuzzy: |label| {
+        if label.contains("农场") {
+            Some(Building::SuperFarm)
+        } else if label.contains("矿机") {
+            Some(Building::SuperMiner)
+        } else {
+            None
+        }
+    },
 }
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
+#[serde(rename_all_fields = "lowercase")]
 pub enum PlayerToServerMessage {
+    /// 连接后的第一帧，之后再发送会被拒绝
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
     Investment { action: InvestmentAction },
     Bid { action: BidAction },
+    Trade { action: TradeAction },
+    RequestSnapshot,
+    Ready,
+    Unready,
+    StartGame,
 }
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", content = "target")]
 #[serde(rename_all = "lowercase")]
 #[serde(rename_all_fields = "lowercase")]
 pub enum ServerToPlayerMessage {
-    #[serde(serialize_with = "serialize_stp_broadcast")]
+    Welcome {
+        server_version: Cow<'static, str>,
+        protocol_version: u32,
+        rules_summary: RulesSummary,
+        session_token: String,
+    },
+    #[serde(serialize_with = "serialize_stp_broadcast", deserialize_with = "deserialize_stp_broadcast")]
+    #[schemars(with = "ServerBroadcastMessage")]
     Broadcast { raw: ServerBroadcastMessage },
+    Snapshot {
+        version: u64,
+        state: GameStateResponse,
+        player: Option<PlayerInfoResponse>,
+    },
+    StateDiff { version: u64, diffs: Vec<StateDiff> },
+    ActionAccepted { outcome: ActionOutcome },
+    ActionRejected { reason: String },
+    TradeOffered { offer: TradeOfferResponse },
+    PlayerStateUpdate {
+        carried_over: u32,
+        refill: u32,
+        info: PlayerInfoResponse,
+    },
+    TradeRejected { id: u32, by: Cow<'static, str> },
 }
 fn serialize_stp_broadcast<S>(
     raw: &ServerBroadcastMessage,
@@ -108,29 +265,172 @@ where
 {
     raw.serialize(serializer)
 }
-#[derive(Clone, Serialize)]
+fn deserialize_stp_broadcast<'de, D>(deserializer: D) -> Result<ServerBroadcastMessage, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    ServerBroadcastMessage::deserialize(deserializer)
+}
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", content = "target")]
 #[serde(rename_all = "lowercase")]
 #[serde(rename_all_fields = "lowercase")]
 pub enum ServerBroadcastMessage {
-    PhaseChanged { epoch: u32, phase: u32 },
+    PhaseChanged {
+        epoch: u32,
+        phase: u32,
+        remaining_secs: Option<u32>,
+    },
+    TurnChanged {
+        player: Cow<'static, str>,
+        remaining_secs: Option<u32>,
+    },
+    TimedOut { player: Cow<'static, str> },
+    PlayerAfk { player: Cow<'static, str> },
     DataRequired { epoch: u32, phase: u32 },
+    WaitingOn { players: Vec<Cow<'static, str>> },
     GameStart,
+    Lobby { host: Option<Cow<'static, str>>, ready: Vec<Cow<'static, str>> },
+    Countdown { seconds: u32 },
+    CountdownCancelled,
+    TradeCompleted { offer: TradeOfferResponse },
+    Attack {
+        attacker: Cow<'static, str>,
+        target: Cow<'static, str>,
+        aim: AttackTarget,
+        hit: bool,
+        destroyed: u32,
+    },
+    Starved { player: Cow<'static, str>, missing_food: u32 },
+}
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
+#[serde(rename_all_fields = "lowercase")]
+pub enum ActionOutcome {
+    Done,
+    TradeProposed { id: u32 },
+    Exchanged {
+        from: Items,
+        from_amount: u32,
+        to: Items,
+        to_amount: u32,
+        fee_percent: u32,
+    },
+    Produced {
+        consumed: HashMap<Items, u32>,
+        produced: HashMap<Items, u32>,
+    },
+    Attacked { hit: bool, destroyed: u32 },
+    Upgraded { from: Building, to: Building },
+    Explored {
+        /// 探索事件的 id，显示名称在快照的 `names` 里
+        event: String,
+        gained: HashMap<Items, u32>,
+        lost: HashMap<Items, u32>,
+    },
+}
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
+#[serde(rename_all_fields = "lowercase")]
+pub enum StateDiff {
+    Phase { epoch: u32, phase: u32 },
+    Players { players: Vec<Cow<'static, str>> },
+    Market { market: Vec<Items> },
+    Prices { values: HashMap<Items, u32> },
+    ActionPoints { player: Cow<'static, str>, action_points: u32 },
+    Buildings { player: Cow<'static, str>, buildings: Vec<Building> },
+    Resources { player: Cow<'static, str>, resources: HashMap<Items, u32> },
+    BankMoney { player: Cow<'static, str>, bank_money: u32 },
+}
+impl StateDiff {
+    /// 资源和存款属于私有信息，只推送给玩家本人，观战者永远看不到
+    pub fn private_to(&self) -> Option<&str> {
+        match self {
+            StateDiff::Resources { player, .. } | StateDiff::BankMoney { player, .. } => Some(player.as_ref()),
+            _ => None,
+        }
+    }
 }
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
+#[serde(rename_all_fields = "lowercase")]
 pub enum InvestmentAction {
     Explore,
-    Exchange,
+    Exchange { from: Items, to: Items, amount: u32 },
     Build(Building),
     Ore,
-    Pick,
-    Mine,
-    Bank(u32),
+    Pick { item: Items },
+    Mine { into: Items, amount: u32 },
+    Bank(BankAction),
+    Attack { target: String, aim: AttackTarget },
+    Upgrade(Building),
     End,
 }
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
+pub enum BankAction {
+    Deposit(u32),
+    Withdraw(u32),
+}
+#[derive(Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
+pub enum AttackTarget {
+    Building(Building),
+    Stockpile(Items),
+}
+impl From<&AttackTarget> for &'static str {
+    fn from(value: &AttackTarget) -> Self {
+        match value {
+            AttackTarget::Building(x) => x.into(),
+            AttackTarget::Stockpile(x) => x.into(),
+        }
+    }
+}
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BankRecordKind {
+    Deposit,
+    Withdraw,
+    Interest,
+    /// 存款不受保护时被炮台攻击金币打掉的部分
+    Attacked,
+}
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BankRecord {
+    pub epoch: u32,
+    pub kind: BankRecordKind,
+    /// 余额的变动，取出和被攻击时为负数
+    pub amount: i64,
+    pub balance: u32,
+}
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
 pub enum BidAction {
     PlaceBid(u32),
     TakeItem(u32),
     EndTake,
 }
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", content = "target")]
+#[serde(rename_all = "lowercase")]
+#[serde(rename_all_fields = "lowercase")]
+pub enum TradeAction {
+    Offer {
+        to: String,
+        give: HashMap<Items, u32>,
+        want: HashMap<Items, u32>,
+    },
+    Accept { id: u32 },
+    Reject { id: u32 },
+    Counter {
+        id: u32,
+        give: HashMap<Items, u32>,
+        want: HashMap<Items, u32>,
+    },
+}
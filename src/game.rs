@@ -1,32 +1,356 @@
-use crate::AppState;
-use crate::enums::ServerBroadcastMessage;
+use crate::config::{GameCfg, LobbyCfg};
+use crate::enums::{
+    ActionOutcome, BidAction, InvestmentAction, PlayerToServerMessage, ServerBroadcastMessage, ServerToPlayerMessage,
+};
+use crate::rules::{self, PHASE_BID, PHASE_INVESTMENT, PHASE_SETTLEMENT, PHASE_TAKE};
+use crate::{trade, ActionError, AppState, GameState, PlayerRequest};
+use std::borrow::Cow;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
-pub async fn game_main_loop(app_state: Arc<AppState>) {
-    let (total_epochs, required_players) = {
+const TICK: Duration = Duration::from_millis(100);
+
+/// 取出所有玩家已经发来但还没处理的消息，同一玩家的消息保持原有顺序
+async fn drain_messages(app_state: &AppState) -> Vec<(&'static str, PlayerRequest)> {
+    let game_state = app_state.game_state.read().await;
+    let mut res = Vec::new();
+    for (&name, player) in game_state.players.iter() {
+        let mut receiver = player.from_channel.receiver.lock().await;
+        while let Ok(msg) = receiver.try_recv() {
+            res.push((name, msg));
+        }
+    }
+    res
+}
+fn handle_lobby_message(
+    game_state: &mut GameState,
+    lobby_cfg: &LobbyCfg,
+    player_name: &'static str,
+    msg: PlayerToServerMessage,
+    start_requested: &mut bool,
+) -> Result<ActionOutcome, ActionError> {
+    match msg {
+        PlayerToServerMessage::Ready | PlayerToServerMessage::Unready => {
+            if let Some(player) = game_state.players.get_mut(player_name) {
+                player.ready = matches!(msg, PlayerToServerMessage::Ready);
+            }
+            Ok(ActionOutcome::Done)
+        }
+        PlayerToServerMessage::StartGame => {
+            if game_state.host() != Some(player_name) {
+                return Err(ActionError::NotHost);
+            }
+            let ready = game_state.ready_players().len() as u32;
+            if ready < lobby_cfg.min_ready_players {
+                return Err(ActionError::NotEnoughReady(ready, lobby_cfg.min_ready_players));
+            }
+            *start_requested = true;
+            Ok(ActionOutcome::Done)
+        }
+        PlayerToServerMessage::Hello { .. } => Err(ActionError::AlreadyGreeted),
+        _ => Err(ActionError::NotStarted),
+    }
+}
+impl GameState {
+    pub fn ready_players(&self) -> Vec<&'static str> {
+        self.join_order
+            .iter()
+            .filter(|x| self.players.get(**x).is_some_and(|p| p.ready))
+            .cloned()
+            .collect()
+    }
+    fn lobby_message(&self) -> ServerBroadcastMessage {
+        ServerBroadcastMessage::Lobby {
+            host: self.host().map(Cow::Borrowed),
+            ready: self.ready_players().into_iter().map(Cow::Borrowed).collect(),
+        }
+    }
+}
+/// 等待玩家准备，直到人满且全部准备，或房主在足够玩家准备后手动开始。
+/// 返回前会把游戏标记为已开始，之后不再接受新玩家加入
+async fn run_lobby(app_state: &AppState) {
+    let (required_players, lobby_cfg) = {
         let config = app_state.cfg.lock().await;
         (
-            config.game_rules.prepare.total_epochs,
             config.server.player_numbers,
+            config.server.lobby.clone(),
         )
     };
+    let mut start_requested = false;
+    let mut countdown: Option<(Instant, u32)> = None;
+    let mut last_lobby = None;
     loop {
-        {
-            let game_state = app_state.game_state.read().await;
-            if game_state.players.len() as u32 >= required_players {
-                break;
+        let messages = drain_messages(app_state).await;
+        let mut game_state = app_state.game_state.write().await;
+        for (name, request) in messages {
+            let result = handle_lobby_message(&mut game_state, &lobby_cfg, name, request.msg, &mut start_requested);
+            game_state.reply(name, result, request.respond_to).await;
+        }
+        let lobby = (game_state.host(), game_state.ready_players());
+        if last_lobby.as_ref() != Some(&lobby) {
+            game_state.broadcast(game_state.lobby_message()).await;
+            last_lobby = Some(lobby);
+        }
+        game_state.publish_diffs().await;
+        let ready = game_state.ready_players().len() as u32;
+        let table_full = game_state.players.len() as u32 >= required_players
+            && (!lobby_cfg.require_ready || ready == game_state.players.len() as u32);
+        start_requested &= ready >= lobby_cfg.min_ready_players;
+        let can_start = table_full || start_requested;
+        match countdown {
+            None if can_start => {
+                if lobby_cfg.countdown_secs == 0 {
+                    game_state.started = true;
+                    break;
+                }
+                countdown = Some((
+                    Instant::now() + Duration::from_secs(lobby_cfg.countdown_secs as u64),
+                    lobby_cfg.countdown_secs,
+                ));
+                game_state
+                    .broadcast(ServerBroadcastMessage::Countdown {
+                        seconds: lobby_cfg.countdown_secs,
+                    })
+                    .await;
+            }
+            Some(_) if !can_start => {
+                countdown = None;
+                game_state
+                    .broadcast(ServerBroadcastMessage::CountdownCancelled)
+                    .await;
+            }
+            Some((deadline, announced)) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    game_state.started = true;
+                    break;
+                }
+                let seconds = remaining.as_secs_f32().ceil() as u32;
+                if seconds < announced {
+                    countdown = Some((deadline, seconds));
+                    game_state
+                        .broadcast(ServerBroadcastMessage::Countdown { seconds })
+                        .await;
+                }
             }
+            None => {}
+        }
+        drop(game_state);
+        tokio::time::sleep(TICK).await;
+    }
+}
+/// 玩家发来任何消息都说明人还在，清掉超时计数和挂机标记
+pub async fn handle_game_message(
+    game_state: &mut GameState,
+    cfg: &GameCfg,
+    player_name: &'static str,
+    msg: PlayerToServerMessage,
+) -> Result<ActionOutcome, ActionError> {
+    if let Some(player) = game_state.players.get_mut(player_name) {
+        player.timeouts = 0;
+        player.afk = false;
+    }
+    match msg {
+        PlayerToServerMessage::Investment { action } => rules::apply_investment(game_state, cfg, player_name, action).await,
+        PlayerToServerMessage::Bid { action } => rules::apply_bid(game_state, cfg, player_name, action),
+        PlayerToServerMessage::Trade { action } => trade::apply_trade(game_state, cfg, player_name, action).await,
+        PlayerToServerMessage::RequestSnapshot => Ok(ActionOutcome::Done),
+        PlayerToServerMessage::Hello { .. } => Err(ActionError::AlreadyGreeted),
+        PlayerToServerMessage::Ready | PlayerToServerMessage::Unready | PlayerToServerMessage::StartGame => {
+            Err(ActionError::AlreadyStarted)
         }
     }
+}
+fn phase_time_limit(phase: u32, cfg: &GameCfg) -> Option<u32> {
+    let timers = &cfg.game_rules.timers;
+    if !timers.enable {
+        return None;
+    }
+    match phase {
+        PHASE_BID => Some(timers.bid_secs),
+        PHASE_TAKE => Some(timers.take_turn_secs),
+        PHASE_INVESTMENT => Some(timers.investment_secs),
+        _ => None,
+    }
+}
+fn deadline_after(secs: Option<u32>) -> Option<Instant> {
+    secs.map(|x| Instant::now() + Duration::from_secs(x as u64))
+}
+async fn begin_phase(game_state: &mut GameState, cfg: &GameCfg) {
+    let limit = phase_time_limit(game_state.phase, cfg);
+    game_state.taken_this_turn = 0;
+    game_state.deadline = deadline_after(limit);
+    game_state.trades.clear();
+    game_state.waiting_on = match game_state.phase {
+        PHASE_BID => {
+            game_state.bids.clear();
+            game_state.join_order.clone()
+        }
+        PHASE_TAKE if !game_state.market.is_empty() => {
+            let mut order = game_state.join_order.clone();
+            order.sort_by_key(|x| std::cmp::Reverse(game_state.bids.get(x).cloned().unwrap_or(0)));
+            order
+        }
+        PHASE_INVESTMENT if cfg.game_rules.investment.enable => game_state.join_order.clone(),
+        PHASE_SETTLEMENT => {
+            rules::settle_epoch(game_state, cfg).await;
+            Vec::new()
+        }
+        _ => Vec::new(),
+    };
+    game_state
+        .broadcast(ServerBroadcastMessage::PhaseChanged {
+            epoch: game_state.epoch,
+            phase: game_state.phase,
+            remaining_secs: limit,
+        })
+        .await;
+    if game_state.phase == PHASE_TAKE
+        && let Some(&player) = game_state.waiting_on.first()
     {
-        let game_state = app_state.game_state.read().await;
         game_state
-            .broadcast(ServerBroadcastMessage::GameStart)
+            .broadcast(ServerBroadcastMessage::TurnChanged {
+                player: player.into(),
+                remaining_secs: limit,
+            })
             .await;
     }
+    if !game_state.waiting_on.is_empty() {
+        game_state
+            .broadcast(ServerBroadcastMessage::DataRequired {
+                epoch: game_state.epoch,
+                phase: game_state.phase,
+            })
+            .await;
+        game_state
+            .broadcast(ServerBroadcastMessage::WaitingOn {
+                players: game_state.waiting_on.iter().map(|x| Cow::Borrowed(*x)).collect(),
+            })
+            .await;
+    }
+}
+/// 给还没有回应的玩家单独再发一次 DataRequired，拿取阶段只提醒正在拿取的玩家
+async fn reprompt(game_state: &GameState) {
+    let players = if game_state.phase == PHASE_TAKE {
+        game_state.waiting_on.iter().take(1).cloned().collect::<Vec<&'static str>>()
+    } else {
+        game_state.waiting_on.clone()
+    };
+    for player_name in players {
+        game_state
+            .send_to(
+                player_name,
+                ServerToPlayerMessage::Broadcast {
+                    raw: ServerBroadcastMessage::DataRequired {
+                        epoch: game_state.epoch,
+                        phase: game_state.phase,
+                    },
+                },
+            )
+            .await;
+    }
+}
+/// 时间用完的玩家，以及已被标记为挂机的玩家，由服务器代为执行默认操作
+pub async fn apply_timeouts(game_state: &mut GameState, cfg: &GameCfg) {
+    let expired = game_state.deadline.is_some_and(|x| Instant::now() >= x);
+    let candidates = if game_state.phase == PHASE_TAKE {
+        game_state.waiting_on.iter().take(1).cloned().collect::<Vec<&'static str>>()
+    } else {
+        game_state.waiting_on.clone()
+    };
+    for player_name in candidates {
+        let afk = game_state.players.get(player_name).is_some_and(|x| x.afk);
+        if !expired && !afk {
+            continue;
+        }
+        let _ = match game_state.phase {
+            PHASE_BID => rules::apply_bid(game_state, cfg, player_name, BidAction::PlaceBid(0)),
+            PHASE_TAKE => rules::apply_bid(game_state, cfg, player_name, BidAction::EndTake),
+            _ => rules::apply_investment(game_state, cfg, player_name, InvestmentAction::End).await,
+        };
+        if afk {
+            continue;
+        }
+        game_state
+            .broadcast(ServerBroadcastMessage::TimedOut { player: player_name.into() })
+            .await;
+        let afk_after = cfg.game_rules.timers.afk_after_timeouts;
+        if let Some(player) = game_state.players.get_mut(player_name) {
+            player.timeouts += 1;
+            if afk_after > 0 && player.timeouts >= afk_after {
+                player.afk = true;
+                game_state
+                    .broadcast(ServerBroadcastMessage::PlayerAfk { player: player_name.into() })
+                    .await;
+            }
+        }
+    }
+}
+/// 在当前阶段内循环处理玩家消息和超时，直到没有需要等待的玩家
+async fn run_phase(app_state: &AppState) {
+    // 复制一份配置，不在整个阶段里占着配置锁，HTTP 鉴权也要用到它
+    let cfg = app_state.cfg.lock().await.clone();
     {
         let mut game_state = app_state.game_state.write().await;
-        game_state.started = true;
+        begin_phase(&mut game_state, &cfg).await;
+        game_state.publish_diffs().await;
+    }
+    let mut last_prompt = Instant::now();
+    loop {
+        let messages = drain_messages(app_state).await;
+        let mut game_state = app_state.game_state.write().await;
+        let current = game_state.waiting_on.first().cloned();
+        let waiting_before = game_state.waiting_on.clone();
+        for (name, request) in messages {
+            let result = handle_game_message(&mut game_state, &cfg, name, request.msg).await;
+            game_state.reply(name, result, request.respond_to).await;
+        }
+        apply_timeouts(&mut game_state, &cfg).await;
+        if game_state.phase == PHASE_TAKE
+            && game_state.waiting_on.first().cloned() != current
+            && let Some(&player) = game_state.waiting_on.first()
+        {
+            let limit = phase_time_limit(PHASE_TAKE, &cfg);
+            game_state.deadline = deadline_after(limit);
+            game_state
+                .broadcast(ServerBroadcastMessage::TurnChanged {
+                    player: player.into(),
+                    remaining_secs: limit,
+                })
+                .await;
+        }
+        if game_state.waiting_on != waiting_before && !game_state.waiting_on.is_empty() {
+            game_state
+                .broadcast(ServerBroadcastMessage::WaitingOn {
+                    players: game_state.waiting_on.iter().map(|x| Cow::Borrowed(*x)).collect(),
+                })
+                .await;
+        }
+        let reprompt_secs = cfg.game_rules.timers.reprompt_secs;
+        if reprompt_secs > 0 && last_prompt.elapsed() >= Duration::from_secs(reprompt_secs as u64) {
+            reprompt(&game_state).await;
+            last_prompt = Instant::now();
+        }
+        game_state.publish_diffs().await;
+        if game_state.waiting_on.is_empty() {
+            break;
+        }
+        drop(game_state);
+        tokio::time::sleep(TICK).await;
+    }
+}
+pub async fn game_main_loop(app_state: Arc<AppState>) {
+    let total_epochs = {
+        let config = app_state.cfg.lock().await;
+        config.game_rules.prepare.total_epochs
+    };
+    run_lobby(&app_state).await;
+    {
+        let game_state = app_state.game_state.read().await;
+        game_state
+            .broadcast(ServerBroadcastMessage::GameStart)
+            .await;
     }
     loop {
         {
@@ -34,14 +358,16 @@ pub async fn game_main_loop(app_state: Arc<AppState>) {
                 break;
             }
         }
-        /* TODO: 完成游戏逻辑 */
+        run_phase(&app_state).await;
         {
             let mut game_state = app_state.game_state.write().await;
-            game_state.phase += 1;
-            if game_state.phase == 5 {
+            if game_state.phase >= PHASE_SETTLEMENT {
                 game_state.epoch += 1;
-                game_state.phase = 0;
+                game_state.phase = PHASE_BID;
+            } else {
+                game_state.phase += 1;
             }
+            game_state.publish_diffs().await;
         }
     }
 }
@@ -1,15 +1,37 @@
+#[cfg(feature = "client")]
+pub mod client;
+pub mod codec;
 pub mod config;
+pub mod diff;
 pub mod dtos;
 pub mod enums;
 pub mod game;
+pub mod locale;
+pub mod routes;
+pub mod rules;
+pub mod schema;
+pub mod trade;
 
 use crate::config::GameCfg;
-use crate::enums::{Building, Items, PlayerToServerMessage, ServerBroadcastMessage, ServerToPlayerMessage};
-use tokio::sync::{Mutex, RwLock};
+use crate::diff::PublishedState;
+use crate::locale::Locale;
+use crate::trade::TradeOffer;
+use crate::dtos::{GameStateResponse, PlayerInfoResponse};
+use crate::enums::{ActionOutcome, BankRecord, Building, Items, PlayerToServerMessage, ServerBroadcastMessage, ServerToPlayerMessage};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{Mutex, RwLock, oneshot};
 use rand::prelude::SliceRandom;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use std::collections::HashMap;
 use std::sync::Arc;
 use thiserror::Error;
+use tracing::warn;
+use tokio::time::Instant;
+/// 当前协议版本，消息格式有不兼容的改动时递增
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 服务器仍然支持的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
 #[derive(Error, Debug)]
 pub enum NoSuchFound {
     #[error("你传入的{0}物品无法找到对应枚举量")]
@@ -17,6 +39,157 @@ pub enum NoSuchFound {
     #[error("你传入的{0}建筑无法找到对应枚举量")]
     NoSuchBuildings(&'static str)
 }
+impl NoSuchFound {
+    pub fn message(&self, locale: Locale) -> String {
+        match locale {
+            Locale::Zh => self.to_string(),
+            Locale::En => match self {
+                NoSuchFound::NoSuchItems(x) => format!("No item matches `{}`", x),
+                NoSuchFound::NoSuchBuildings(x) => format!("No building matches `{}`", x),
+            },
+        }
+    }
+}
+/// 开局后重新连接失败的原因
+#[derive(Error, Debug)]
+pub enum JoinError {
+    #[error("玩家{0}不存在")]
+    NoSuchPlayer(String),
+    #[error("玩家{0}已经在线")]
+    AlreadyConnected(String),
+    #[error("重连需要上次 Welcome 里的会话令牌")]
+    MissingSessionToken,
+    #[error("会话令牌无效")]
+    InvalidSessionToken,
+    #[error("房间已满（{0}人）")]
+    LobbyFull(u32),
+}
+impl JoinError {
+    pub fn message(&self, locale: Locale) -> String {
+        match locale {
+            Locale::Zh => self.to_string(),
+            Locale::En => match self {
+                JoinError::NoSuchPlayer(x) => format!("Player {} does not exist", x),
+                JoinError::AlreadyConnected(x) => format!("Player {} is already connected", x),
+                JoinError::MissingSessionToken => "Reconnecting requires the session token from the last Welcome".to_string(),
+                JoinError::InvalidSessionToken => "Invalid session token".to_string(),
+                JoinError::LobbyFull(x) => format!("The game is full ({} players)", x),
+            },
+        }
+    }
+}
+#[derive(Error, Debug)]
+pub enum ActionError {
+    #[error("游戏已经开始")]
+    AlreadyStarted,
+    #[error("游戏尚未开始")]
+    NotStarted,
+    #[error("只有房主可以开始游戏")]
+    NotHost,
+    #[error("准备的玩家不足：{0}/{1}")]
+    NotEnoughReady(u32, u32),
+    #[error("当前阶段不能进行这个操作")]
+    WrongPhase,
+    #[error("还没有轮到你")]
+    NotYourTurn,
+    #[error("你在这个阶段已经完成行动")]
+    AlreadyDone,
+    #[error("行动点不足：需要{0}，只有{1}")]
+    NotEnoughAp(u32, u32),
+    #[error("{}不足", .0.display_name(Locale::Zh))]
+    NotEnoughItems(Items),
+    #[error("市场上没有第{0}个物品")]
+    NoSuchMarketItem(u32),
+    #[error("投资阶段已被关闭")]
+    InvestmentDisabled,
+    #[error("玩家{0}不存在")]
+    NoSuchPlayer(String),
+    #[error("交易{0}不存在")]
+    NoSuchTrade(u32),
+    #[error("交易已被关闭")]
+    TradeDisabled,
+    #[error("无效的交易：不能和自己交易")]
+    TradeWithSelf,
+    #[error("无效的交易：交易内容为空")]
+    EmptyTrade,
+    #[error("兑换数量太少，换不到任何{}", .0.display_name(Locale::Zh))]
+    ExchangeTooSmall(Items),
+    #[error("不能把{}换成它自己", .0.display_name(Locale::Zh))]
+    ExchangeSameItem(Items),
+    #[error("本轮兑换额度不足，还剩{0}")]
+    ExchangeLimit(u32),
+    #[error("没有配置任何探索事件")]
+    NoExploreEvents,
+    #[error("{}无法徒手采集", .0.display_name(Locale::Zh))]
+    CannotPick(Items),
+    #[error("{}无法通过冶炼得到", .0.display_name(Locale::Zh))]
+    CannotMine(Items),
+    #[error("需要先拥有{}", .0.display_name(Locale::Zh))]
+    MissingBuilding(Building),
+    #[error("银行存款不足：只有{0}")]
+    NotEnoughBankMoney(u32),
+    #[error("攻击已被关闭")]
+    CombatDisabled,
+    #[error("不能攻击自己")]
+    AttackSelf,
+    #[error("本轮攻击次数已用完")]
+    AttackLimit,
+    #[error("玩家{}没有{}", .0, .1.display_name(Locale::Zh))]
+    TargetMissingBuilding(String, Building),
+    #[error("{}无法升级", .0.display_name(Locale::Zh))]
+    CannotUpgrade(Building),
+    #[error("{}无法直接建造", .0.display_name(Locale::Zh))]
+    CannotBuild(Building),
+    #[error("{}已经达到数量上限", .0.display_name(Locale::Zh))]
+    BuildingLimit(Building),
+    #[error("已经完成握手")]
+    AlreadyGreeted,
+    #[error("数量{0}太大")]
+    AmountTooLarge(u32),
+}
+impl ActionError {
+    /// 发给客户端的拒绝原因，按玩家连接时选择的语言生成
+    pub fn message(&self, locale: Locale) -> String {
+        if locale == Locale::Zh {
+            return self.to_string();
+        }
+        match self {
+            ActionError::AlreadyStarted => "The game has already started".to_string(),
+            ActionError::NotStarted => "The game has not started yet".to_string(),
+            ActionError::NotHost => "Only the host can start the game".to_string(),
+            ActionError::NotEnoughReady(x, y) => format!("Not enough players are ready: {}/{}", x, y),
+            ActionError::WrongPhase => "This action is not allowed in the current phase".to_string(),
+            ActionError::NotYourTurn => "It is not your turn".to_string(),
+            ActionError::AlreadyDone => "You have already finished this phase".to_string(),
+            ActionError::NotEnoughAp(x, y) => format!("Not enough action points: need {}, have {}", x, y),
+            ActionError::NotEnoughItems(x) => format!("Not enough {}", x.display_name(locale)),
+            ActionError::NoSuchMarketItem(x) => format!("There is no market item #{}", x),
+            ActionError::InvestmentDisabled => "The investment phase is disabled".to_string(),
+            ActionError::NoSuchPlayer(x) => format!("Player {} does not exist", x),
+            ActionError::NoSuchTrade(x) => format!("Trade {} does not exist", x),
+            ActionError::TradeDisabled => "Trading is disabled".to_string(),
+            ActionError::TradeWithSelf => "Invalid trade: you cannot trade with yourself".to_string(),
+            ActionError::EmptyTrade => "Invalid trade: nothing to trade".to_string(),
+            ActionError::ExchangeTooSmall(x) => format!("The amount is too small to get any {}", x.display_name(locale)),
+            ActionError::ExchangeSameItem(x) => format!("{} cannot be exchanged for itself", x.display_name(locale)),
+            ActionError::ExchangeLimit(x) => format!("Exchange limit reached for this epoch, {} left", x),
+            ActionError::NoExploreEvents => "No explore events are configured".to_string(),
+            ActionError::CannotPick(x) => format!("{} cannot be picked by hand", x.display_name(locale)),
+            ActionError::CannotMine(x) => format!("{} cannot be produced by mining", x.display_name(locale)),
+            ActionError::MissingBuilding(x) => format!("You need a {} first", x.display_name(locale)),
+            ActionError::NotEnoughBankMoney(x) => format!("Not enough money in the bank: only {}", x),
+            ActionError::CombatDisabled => "Attacks are disabled".to_string(),
+            ActionError::AttackSelf => "You cannot attack yourself".to_string(),
+            ActionError::AttackLimit => "No attacks left for this epoch".to_string(),
+            ActionError::TargetMissingBuilding(x, y) => format!("Player {} has no {}", x, y.display_name(locale)),
+            ActionError::CannotUpgrade(x) => format!("{} cannot be upgraded", x.display_name(locale)),
+            ActionError::CannotBuild(x) => format!("{} cannot be built directly", x.display_name(locale)),
+            ActionError::BuildingLimit(x) => format!("You already have the maximum number of {}", x.display_name(locale)),
+            ActionError::AlreadyGreeted => "The handshake has already been completed".to_string(),
+            ActionError::AmountTooLarge(x) => format!("The amount {} is too large", x),
+        }
+    }
+}
 
 pub struct AppState {
     pub cfg: Arc<Mutex<GameCfg>>,
@@ -43,35 +216,98 @@ impl<T> Channel<T> {
         }
     }
 }
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+pub struct UnboundedChannel<T> {
+    pub sender: tokio::sync::mpsc::UnboundedSender<T>,
+    pub receiver: Arc<Mutex<tokio::sync::mpsc::UnboundedReceiver<T>>>,
+}
+impl<T> UnboundedChannel<T> {
+    pub fn new() -> UnboundedChannel<T> {
+        let (s, r) = tokio::sync::mpsc::unbounded_channel();
+        UnboundedChannel {
+            sender: s,
+            receiver: Arc::new(Mutex::new(r)),
+        }
+    }
+}
+impl<T> Default for UnboundedChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+/// 玩家发来的一条消息，通过 HTTP 发送的操作附带回执通道
+pub struct PlayerRequest {
+    pub msg: PlayerToServerMessage,
+    pub respond_to: Option<oneshot::Sender<ServerToPlayerMessage>>,
+}
+impl From<PlayerToServerMessage> for PlayerRequest {
+    fn from(value: PlayerToServerMessage) -> Self {
+        Self {
+            msg: value,
+            respond_to: None,
+        }
+    }
+}
 pub struct Player {
     pub resources: HashMap<Items, u32>,
     pub action_points: u32,
     pub buildings: Vec<Building>,
     pub bank_money: u32,
-    pub from_channel: Channel<PlayerToServerMessage>,
+    pub bank_history: Vec<BankRecord>,
+    pub connected: bool,
+    pub ready: bool,
+    pub timeouts: u32,
+    pub afk: bool,
+    pub exchanged_this_epoch: u32,
+    pub attacks_this_epoch: u32,
+    pub shut_down_buildings: Vec<Building>,
+    pub ap_penalty: u32,
+    pub penalty_points: u32,
+    /// 连接时协商的语言，重连时会更新
+    pub locale: Locale,
+    /// 握手成功后发给客户端的会话令牌，每次连接都会重新生成
+    pub session_token: Option<String>,
+    pub from_channel: Channel<PlayerRequest>,
     pub to_channel: Channel<ServerToPlayerMessage>,
 }
 impl Player {
     pub fn new() -> Player {
-        let mut res = Self {
+        Self {
             resources: HashMap::new(),
             action_points: 0,
             buildings: Vec::new(),
             bank_money: 0,
+            bank_history: Vec::new(),
+            connected: true,
+            ready: false,
+            timeouts: 0,
+            afk: false,
+            exchanged_this_epoch: 0,
+            attacks_this_epoch: 0,
+            shut_down_buildings: Vec::new(),
+            ap_penalty: 0,
+            penalty_points: 0,
+            locale: Locale::default(),
+            session_token: None,
             from_channel: Channel::new(),
             to_channel: Channel::new(),
-        };
-        res.resources.insert(Items::Gold, 0);
-        res.resources.insert(Items::Iron, 0);
-        res.resources.insert(Items::Wood, 0);
-        res.resources.insert(Items::Diamond, 0);
-        res.resources.insert(Items::Ore, 0);
-        res.resources.insert(Items::Food, 0);
-        res
+        }
+    }
+    /// 调用方通常持有游戏状态的写锁，不能等待发送通道。
+    /// 通道满了说明客户端读得太慢，丢弃这条消息，客户端发现版本号不连续时可以请求快照
+    pub fn push(&self, player_name: &str, message: ServerToPlayerMessage) {
+        if let Err(TrySendError::Full(_)) = self.to_channel.sender.try_send(message) {
+            warn!("{} 的发送队列已满，丢弃一条消息", player_name);
+        }
     }
     pub fn with_cfg(cfg: &GameCfg) -> Player {
         let mut res = Self::new();
         cfg.game_rules.prepare.defaults_give_player.apply_to_player(&mut res);
+        cfg.game_rules.items.apply_to_player(&mut res);
         res
     }
 }
@@ -80,74 +316,210 @@ impl Default for Player {
         Self::new()
     }
 }
+/// 观战消息要延迟发送，积压的条数随延迟增长，丢掉任何一条都会让观战画面错乱，
+/// 所以队列不设上限，内存由 `max_spectators` 和延迟秒数限制
+pub struct Spectator {
+    pub to_channel: UnboundedChannel<(Instant, ServerToPlayerMessage)>,
+}
+impl Spectator {
+    pub fn new() -> Spectator {
+        Self {
+            to_channel: UnboundedChannel::new(),
+        }
+    }
+}
+impl Default for Spectator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 pub struct GameState {
     pub players: HashMap<&'static str, Player>,
+    pub join_order: Vec<&'static str>,
+    pub spectators: HashMap<u32, Spectator>,
+    next_spectator_id: u32,
     pub market: Vec<Items>,
     pub current_deck: Vec<Items>,
     pub epoch: u32,
     pub phase: u32,
     pub resource_values: HashMap<Items, u32>,
-    pub started: bool
+    pub started: bool,
+    pub bids: HashMap<&'static str, u32>,
+    /// 当前阶段还需要行动的玩家，拿取阶段按出价排序，第一个就是正在拿取的玩家
+    pub waiting_on: Vec<&'static str>,
+    pub taken_this_turn: u32,
+    pub deadline: Option<Instant>,
+    pub trades: HashMap<u32, TradeOffer>,
+    next_trade_id: u32,
+    pub rng: StdRng,
+    pub version: u64,
+    published: PublishedState,
 }
 impl GameState {
     pub fn new() -> GameState {
-        let mut res = GameState {
+        GameState {
             players: HashMap::new(),
+            join_order: Vec::new(),
+            spectators: HashMap::new(),
+            next_spectator_id: 0,
             market: Vec::new(),
             current_deck: Vec::new(),
             epoch: 1,
             phase: 1,
             resource_values: HashMap::new(),
-            started: false
-        };
-        res.resource_values.insert(Items::Diamond, 8);
-        res.resource_values.insert(Items::Gold, 6);
-        res.resource_values.insert(Items::Wood, 2);
-        res.resource_values.insert(Items::Ore, 3);
-        res.resource_values.insert(Items::Food, 1);
-        res.resource_values.insert(Items::Iron, 2);
-        res
+            started: false,
+            bids: HashMap::new(),
+            waiting_on: Vec::new(),
+            taken_this_turn: 0,
+            deadline: None,
+            trades: HashMap::new(),
+            next_trade_id: 0,
+            rng: rand::make_rng(),
+            version: 0,
+            published: PublishedState::default(),
+        }
     }
     async fn apply_configurations(&mut self, conf: &GameCfg) {
-        self.resource_values = (&conf.game_rules.resource_values_default.clone()).into();
+        self.resource_values = conf.game_rules.items.values();
     }
     pub async fn initialize(&mut self, conf: &GameCfg) {
         self.apply_configurations(conf).await;
-        let deck: HashMap<Items, u32> = (&conf.game_rules.prepare.deck).into();
+        let deck = conf.game_rules.items.deck();
         deck.iter().for_each(|(x, y)| {
             for _ in 0..*y {
-                self.current_deck.push(x.clone())
+                self.current_deck.push(*x)
             }
         });
+        if let Some(seed) = conf.game_rules.prepare.seed {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
         {
-            let mut rng = rand::rng();
-            self.current_deck.shuffle(&mut rng);
+            self.current_deck.shuffle(&mut self.rng);
             let mut cards: Vec<Items> = self.current_deck.drain(0..conf.game_rules.prepare.draw_cards as usize).collect();
             self.market.append(&mut cards);
         }
     }
     pub async fn broadcast(&self, message: ServerBroadcastMessage) {
-        for (_, player) in self.players.iter() {
-            let _ = player.to_channel.sender.send(
-                ServerToPlayerMessage::Broadcast {
-                    raw: message.clone()
-                }
-            ).await;
+        for (name, player) in self.players.iter().filter(|(_, x)| x.connected) {
+            player.push(name, ServerToPlayerMessage::Broadcast {
+                raw: message.clone()
+            });
+        }
+        self.send_spectators(ServerToPlayerMessage::Broadcast { raw: message }).await;
+    }
+    pub async fn send_to(&self, player_name: &str, message: ServerToPlayerMessage) {
+        if let Some(player) = self.players.get(player_name).filter(|x| x.connected) {
+            player.push(player_name, message);
+        }
+    }
+    /// 处理结果总是推送给玩家，请求附带回执通道时再原样返回给请求方
+    pub async fn reply(
+        &self,
+        player_name: &str,
+        result: Result<ActionOutcome, ActionError>,
+        respond_to: Option<oneshot::Sender<ServerToPlayerMessage>>,
+    ) {
+        let message = match result {
+            Ok(outcome) => ServerToPlayerMessage::ActionAccepted { outcome },
+            Err(err) => ServerToPlayerMessage::ActionRejected {
+                reason: err.message(self.players.get(player_name).map(|x| x.locale).unwrap_or_default()),
+            },
+        };
+        if let Some(respond_to) = respond_to {
+            let _ = respond_to.send(message.clone());
+        }
+        self.send_to(player_name, message).await;
+    }
+    /// 通过握手时发放的会话令牌找到玩家
+    pub fn player_by_session(&self, session_token: &str) -> Option<&'static str> {
+        self.players
+            .iter()
+            .find(|(_, x)| x.session_token.as_deref() == Some(session_token))
+            .map(|(name, _)| *name)
+    }
+    /// 最早加入且仍在房间里的玩家就是房主
+    pub fn host(&self) -> Option<&'static str> {
+        self.join_order.first().copied()
+    }
+    /// 断线重连的客户端用它拿到当前版本的完整状态，之后只需应用更高版本的增量
+    /// 快照附带对应语言的显示名称，增量里只有 id
+    pub fn snapshot(&self, player_name: Option<&str>, locale: Locale) -> ServerToPlayerMessage {
+        ServerToPlayerMessage::Snapshot {
+            version: self.version,
+            state: GameStateResponse::from(self).localized(locale),
+            player: player_name
+                .and_then(|x| self.players.get(x))
+                .map(PlayerInfoResponse::from),
+        }
+    }
+    /// 观战者只会收到公开信息，消息附带产生时间，由写出端按配置延迟发送。
+    /// 观战者落后太多时直接丢弃消息，不能让观战拖住对局
+    pub async fn send_spectators(&self, message: ServerToPlayerMessage) {
+        let now = Instant::now();
+        for (_, spectator) in self.spectators.iter() {
+            let _ = spectator.to_channel.sender.send((now, message.clone()));
+        }
+    }
+    pub async fn register_spectator(&mut self, spectator: Spectator, max_spectators: u32) -> Result<u32, String> {
+        if self.spectators.len() as u32 >= max_spectators {
+            Err("Too many spectators".to_string())
+        } else {
+            let id = self.next_spectator_id;
+            self.next_spectator_id += 1;
+            self.spectators.insert(id, spectator);
+            Ok(id)
+        }
+    }
+    pub async fn unregister_spectator(&mut self, id: u32) -> Result<(), String> {
+        if self.spectators.remove(&id).is_none() {
+            Err("Spectator not exist".to_string())
+        } else {
+            Ok(())
         }
     }
     pub async fn register_player(&mut self, player_name: String, player: Player) -> Result<(), String> {
         if self.players.contains_key(player_name.as_str()) {
             Err("Player already exists".to_string())
         } else {
-            self.players.insert(player_name.leak(), player);
+            let player_name: &'static str = player_name.leak();
+            self.players.insert(player_name, player);
+            self.join_order.push(player_name);
             Ok(())
         }
     }
+    /// 对局开始后玩家断线不会被移除，带着上次 Welcome 里的会话令牌重新连接即可接管原来的玩家，
+    /// 只凭服务器 token 不能顶替别人的座位
+    pub async fn reconnect_player(&mut self, player_name: &str, session_token: Option<&str>, locale: Locale) -> Result<(), JoinError> {
+        let player = self.players.get_mut(player_name).ok_or_else(|| JoinError::NoSuchPlayer(player_name.to_string()))?;
+        match session_token {
+            None => Err(JoinError::MissingSessionToken),
+            Some(x) if player.session_token.as_deref() != Some(x) => Err(JoinError::InvalidSessionToken),
+            _ if player.connected => Err(JoinError::AlreadyConnected(player_name.to_string())),
+            _ => {
+                player.connected = true;
+                player.locale = locale;
+                Ok(())
+            }
+        }
+    }
+    pub async fn disconnect_player(&mut self, player_name: String) -> Result<(), String> {
+        if !self.started {
+            return self.unregister_player(player_name).await;
+        }
+        match self.players.get_mut(player_name.as_str()) {
+            None => Err("Player not exist".to_string()),
+            Some(player) => {
+                player.connected = false;
+                Ok(())
+            }
+        }
+    }
     pub async fn unregister_player(&mut self, player_name: String) -> Result<(), String> {
         if !self.players.contains_key(player_name.as_str()) {
             Err("Player not exist".to_string())
         }else {
             self.players.remove(player_name.as_str());
+            self.join_order.retain(|x| *x != player_name.as_str());
             Ok(())
         }
     }
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// 客户端连接时选择的语言，只影响显示名称和错误信息，协议里的 id 不随语言变化
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    Zh,
+    En,
+}
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::Zh, Locale::En];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Locale::Zh => "zh",
+            Locale::En => "en",
+        }
+    }
+    /// 只看主语言标签，`en-US`、`zh-CN` 都能识别
+    pub fn from_code(code: &str) -> Option<Locale> {
+        let primary = code.trim().split(['-', '_']).next().unwrap_or("");
+        Self::ALL
+            .iter()
+            .find(|x| x.code().eq_ignore_ascii_case(primary))
+            .copied()
+    }
+    /// 按 q 值从高到低挑第一个支持的语言
+    pub fn from_accept_language(header: &str) -> Option<Locale> {
+        let mut candidates = header
+            .split(',')
+            .filter_map(|part| {
+                let mut parts = part.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|x| x.trim().strip_prefix("q="))
+                    .and_then(|x| x.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((tag, quality))
+            })
+            .collect::<Vec<(&str, f32)>>();
+        candidates.sort_by(|x, y| y.1.total_cmp(&x.1));
+        candidates
+            .into_iter()
+            .filter(|(_, quality)| *quality > 0.0)
+            .find_map(|(tag, _)| Self::from_code(tag))
+    }
+    /// 查询参数优先于 `Accept-Language`，都没有时使用默认语言
+    pub fn negotiate(query: Option<&str>, accept_language: Option<&str>) -> Locale {
+        query
+            .and_then(Self::from_code)
+            .or_else(|| accept_language.and_then(Self::from_accept_language))
+            .unwrap_or_default()
+    }
+}
+
+/// 物品和建筑在各语言下的显示名称：(id, 语言, 名称)，加载配置时从物品目录和建筑定义中填充
+static DISPLAY_NAMES: RwLock<Vec<(&'static str, &'static str, &'static str)>> = RwLock::new(Vec::new());
+
+pub fn register_names(id: &'static str, names: &HashMap<String, String>) {
+    let mut table = DISPLAY_NAMES.write().unwrap();
+    table.retain(|(x, _, _)| *x != id);
+    for (locale, name) in names.iter() {
+        if let Some(locale) = Locale::from_code(locale) {
+            table.push((id, locale.code(), name.clone().leak()));
+        }
+    }
+}
+pub fn display_name(id: &str, locale: Locale) -> Option<&'static str> {
+    DISPLAY_NAMES
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(x, y, _)| *x == id && *y == locale.code())
+        .map(|(_, _, name)| *name)
+}
+/// 某个语言下全部已知 id 的显示名称，随快照发给客户端
+pub fn display_names(locale: Locale) -> HashMap<&'static str, &'static str> {
+    DISPLAY_NAMES
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, y, _)| *y == locale.code())
+        .map(|(id, _, name)| (*id, *name))
+        .collect()
+}
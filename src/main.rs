@@ -1,15 +1,14 @@
 use std::sync::Arc;
-use axum::middleware::from_fn_with_state;
-use axum::routing::{any, get};
 use tracing::{trace, info, error};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use resource_island_server::GameState;
-use crate::routes::{get_game_state, get_player_info_with_query, get_player_info_with_path, root, ws_handler};
-
-mod routes;
 
 #[tokio::main]
 async fn main(){
+    if std::env::args().nth(1).as_deref() == Some("dump-schema") {
+        println!("{}", serde_json::to_string_pretty(&resource_island_server::schema::schema()).unwrap());
+        return;
+    }
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer())
         .init();
@@ -18,7 +17,7 @@ async fn main(){
     let mut game_state = GameState::new();
     game_state.initialize(&cfg).await;
     if option_env!("RSILS_DEBUG_MODE").is_some() {
-        game_state.players.insert("测试玩家", resource_island_server::Player::new());
+        game_state.register_player("测试玩家".to_string(), resource_island_server::Player::new()).await.unwrap();
 
     }
     let state = Arc::new(resource_island_server::AppState::new(
@@ -26,14 +25,7 @@ async fn main(){
         game_state
     ));
     trace!("正在创建路由");
-    let app = axum::Router::new()
-        .route("/", get(root))
-        .route("/gamestate", get(get_game_state))
-        .route("/playerinfo/{player_name}", get(get_player_info_with_path))
-        .route("/ws/{player_name}", any(ws_handler))
-        .route("/playerinfo", get(get_player_info_with_query))
-        .route_layer(from_fn_with_state(state.clone(), routes::auth_middleware))
-        .with_state(state.clone());
+    let app = resource_island_server::routes::router(state.clone());
     let cfg = state.cfg.lock().await;
     let whole_address = format!("{}:{}", cfg.server.bind_host.clone(), cfg.server.bind_port.clone());
     drop(cfg);
@@ -1,14 +1,25 @@
-use axum::extract::ws::{Message, Utf8Bytes, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket};
 use axum::extract::{Path, Query, Request, State, WebSocketUpgrade};
-use axum::http::StatusCode;
-use axum::middleware::Next;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::{Next, from_fn_with_state};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{any, get, post};
+use axum::Router;
 use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
-use resource_island_server::dtos::{GameStateResponse, PlayerInfoResponse};
-use resource_island_server::{AppState, Player};
+use crate::codec::{self, Encoding, Frame, JSON_SUBPROTOCOL, MSGPACK_SUBPROTOCOL};
+use crate::dtos::{GameStateResponse, PlayerInfoResponse, RulesSummary};
+use crate::enums::{PlayerToServerMessage, ServerToPlayerMessage, with_legacy_labels};
+use crate::locale::Locale;
+use crate::{AppState, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, JoinError, Player, PlayerRequest, Spectator};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
 use tracing::trace;
 
 fn parse_params(input: &str) -> HashMap<String, String> {
@@ -26,6 +37,20 @@ fn parse_params(input: &str) -> HashMap<String, String> {
     }
     map
 }
+/// 查询参数 `lang` 优先，其次是 `Accept-Language` 请求头
+fn request_locale(args: &HashMap<String, String>, headers: &HeaderMap) -> Locale {
+    Locale::negotiate(
+        args.get("lang").map(|x| x.as_str()),
+        headers.get(header::ACCEPT_LANGUAGE).and_then(|x| x.to_str().ok()),
+    )
+}
+/// `compat=labels` 打开兼容模式，物品和建筑按旧版中文名称输出
+fn legacy_labels(args: &HashMap<String, String>) -> bool {
+    args.get("compat").is_some_and(|x| x == "labels")
+}
+fn to_json<T: Serialize>(legacy: bool, value: &T) -> Json<serde_json::Value> {
+    Json(with_legacy_labels(legacy, || serde_json::to_value(value).unwrap()))
+}
 pub async fn auth_middleware(
     state: State<Arc<AppState>>,
     request: Request,
@@ -63,14 +88,26 @@ pub async fn auth_middleware(
 pub async fn root() -> &'static str {
     "You are all set!"
 }
-pub async fn get_game_state(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+pub async fn get_schema() -> impl IntoResponse {
+    Json(crate::schema::schema())
+}
+pub async fn get_game_state(
+    State(state): State<Arc<AppState>>,
+    Query(args): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let locale = request_locale(&args, &headers);
     let state_guard = state.game_state.read().await;
-    (StatusCode::OK, Json(GameStateResponse::from(&*state_guard)))
+    let response = GameStateResponse::from(&*state_guard).localized(locale);
+    (StatusCode::OK, to_json(legacy_labels(&args), &response))
 }
 pub async fn get_player_info_with_path(
     State(state): State<Arc<AppState>>,
     Path(player_name): Path<String>,
+    Query(args): Query<HashMap<String, String>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    let locale = request_locale(&args, &headers);
     let cfg = state.cfg.lock();
     drop(cfg);
     let guard = state.game_state.read().await;
@@ -78,23 +115,25 @@ pub async fn get_player_info_with_path(
         None => {
             return (
                 StatusCode::NOT_FOUND,
-                Json(PlayerInfoResponse::with_error()),
+                to_json(false, &PlayerInfoResponse::with_error()),
             );
         }
         Some(p) => p,
     };
-    (StatusCode::OK, Json(PlayerInfoResponse::from(player)))
+    (StatusCode::OK, to_json(legacy_labels(&args), &PlayerInfoResponse::from(player).localized(locale)))
 }
 pub async fn get_player_info_with_query(
     State(state): State<Arc<AppState>>,
     Query(args): Query<HashMap<String, String>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    let locale = request_locale(&args, &headers);
     let player_name = match args.get("player") {
         Some(name) => name,
         None => {
             return (
                 StatusCode::BAD_REQUEST,
-                Json(PlayerInfoResponse::with_error()),
+                to_json(false, &PlayerInfoResponse::with_error()),
             );
         }
     };
@@ -103,38 +142,204 @@ pub async fn get_player_info_with_query(
         None => {
             return (
                 StatusCode::NOT_FOUND,
-                Json(PlayerInfoResponse::with_error()),
+                to_json(false, &PlayerInfoResponse::with_error()),
             );
         }
         Some(p) => p,
     };
-    (StatusCode::OK, Json(PlayerInfoResponse::from(player)))
+    (StatusCode::OK, to_json(legacy_labels(&args), &PlayerInfoResponse::from(player).localized(locale)))
+}
+/// 开局前注册新玩家，开局后只允许断线的玩家带着会话令牌重连
+async fn join_player(
+    state: &Arc<AppState>,
+    player_name: &str,
+    session_token: Option<&str>,
+    locale: Locale,
+) -> Result<(), (StatusCode, String)> {
+    let (player_obj, player_numbers) = {
+        let cfg_temp = state.cfg.lock().await;
+        let mut player = Player::with_cfg(&cfg_temp);
+        player.locale = locale;
+        (player, cfg_temp.server.player_numbers)
+    };
+    let mut state_temp = state.game_state.write().await;
+    if state_temp.started {
+        state_temp.reconnect_player(player_name, session_token, locale).await.map_err(|err| {
+            let status = match err {
+                JoinError::NoSuchPlayer(_) => StatusCode::NOT_FOUND,
+                JoinError::AlreadyConnected(_) => StatusCode::CONFLICT,
+                JoinError::MissingSessionToken => StatusCode::UNAUTHORIZED,
+                JoinError::InvalidSessionToken => StatusCode::FORBIDDEN,
+                JoinError::LobbyFull(_) => StatusCode::CONFLICT,
+            };
+            (status, err.message(locale))
+        })
+    } else if state_temp.players.len() as u32 >= player_numbers && !state_temp.players.contains_key(player_name) {
+        // 需要全员准备时人满也不会马上开局，不能让多出来的玩家挤进来
+        Err((StatusCode::CONFLICT, JoinError::LobbyFull(player_numbers).message(locale)))
+    } else {
+        state_temp
+            .register_player(player_name.to_string(), player_obj)
+            .await
+            .map_err(|err| (StatusCode::CONFLICT, err))
+    }
+}
+/// 重连时的会话令牌，浏览器的 WebSocket 不能自定义请求头，所以也接受 `session_token` 查询参数
+fn join_session_token<'a>(args: &'a HashMap<String, String>, headers: &'a HeaderMap) -> Option<&'a str> {
+    session_token(headers).or_else(|| args.get("session_token").map(|x| x.as_str()))
 }
 pub async fn ws_handler(
     State(state): State<Arc<AppState>>,
     Path(player_name): Path<String>,
+    Query(args): Query<HashMap<String, String>>,
+    headers: HeaderMap,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    let player_obj = {
-        let cfg_temp = state.cfg.lock().await;
-        Player::with_cfg(&*cfg_temp)
+    let locale = request_locale(&args, &headers);
+    if let Err(err) = join_player(&state, player_name.as_str(), join_session_token(&args, &headers), locale).await {
+        err.into_response()
+    } else {
+        let legacy = legacy_labels(&args);
+        let ws = ws.protocols([JSON_SUBPROTOCOL, MSGPACK_SUBPROTOCOL]);
+        let subprotocol = selected_encoding(&ws);
+        ws.on_upgrade(move |socket| handler_on_upgrade(state, player_name, legacy, subprotocol, socket))
+    }
+}
+fn selected_encoding(ws: &WebSocketUpgrade) -> Option<Encoding> {
+    ws.selected_protocol()
+        .and_then(|x| x.to_str().ok())
+        .and_then(Encoding::from_subprotocol)
+}
+/// 文本帧按 JSON 解码，二进制帧按 MessagePack 解码，与协商的编码无关
+fn decode_message(msg: &Message) -> Option<Result<PlayerToServerMessage, anyhow::Error>> {
+    match msg {
+        Message::Text(text) => Some(codec::decode_text(text.as_str())),
+        Message::Binary(bytes) => Some(codec::decode_binary(bytes)),
+        _ => None,
+    }
+}
+fn encode_message(encoding: Encoding, legacy: bool, msg: &ServerToPlayerMessage) -> Message {
+    match with_legacy_labels(legacy, || encoding.encode(msg).unwrap()) {
+        Frame::Text(text) => Message::Text(Utf8Bytes::from(text)),
+        Frame::Binary(bytes) => Message::Binary(bytes.into()),
+    }
+}
+/// 通过 HTTP 发送操作时携带会话令牌的请求头
+const SESSION_TOKEN_HEADER: &str = "x-session-token";
+/// 握手失败时的关闭码
+const CLOSE_EXPECTED_HELLO: u16 = 4000;
+const CLOSE_UNSUPPORTED_VERSION: u16 = 4001;
+/// 客户端必须在这段时间内发送 Hello
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn check_protocol_version(protocol_version: u32) -> Result<(), (u16, String)> {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+        return Ok(());
+    }
+    Err((
+        CLOSE_UNSUPPORTED_VERSION,
+        format!(
+            "Unsupported protocol version {}, server supports {} to {}",
+            protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ),
+    ))
+}
+/// 生成新的会话令牌并组装 Welcome
+async fn welcome(state: &Arc<AppState>, player_name: &str, protocol_version: u32) -> ServerToPlayerMessage {
+    let rules_summary = RulesSummary::from(&*state.cfg.lock().await);
+    let session_token = format!("{:032x}", rand::random::<u128>());
+    if let Some(player) = state.game_state.write().await.players.get_mut(player_name) {
+        player.session_token = Some(session_token.clone());
+    }
+    ServerToPlayerMessage::Welcome {
+        server_version: env!("CARGO_PKG_VERSION").into(),
+        protocol_version,
+        rules_summary,
+        session_token,
+    }
+}
+/// 第一帧必须是 Hello，协议版本不受支持时返回关闭码和原因。
+/// 成功时返回之后使用的编码：子协议优先，其次是 Hello 里声明的能力
+async fn handshake(
+    state: &Arc<AppState>,
+    player_name: &str,
+    legacy: bool,
+    subprotocol: Option<Encoding>,
+    socket: &mut WebSocket,
+) -> Result<Encoding, (u16, String)> {
+    let hello = match tokio::time::timeout(HELLO_TIMEOUT, socket.recv()).await {
+        Ok(Some(Ok(msg))) => decode_message(&msg).and_then(|x| x.ok()),
+        _ => None,
     };
-    let register_player = {
-        let mut state_temp = state.game_state.write().await;
-        state_temp
-            .register_player(player_name.clone(), player_obj)
-            .await
+    let Some(PlayerToServerMessage::Hello { protocol_version, client_name, capabilities }) = hello else {
+        return Err((CLOSE_EXPECTED_HELLO, "Expected hello as the first frame".to_string()));
     };
-    if let Err(_) = register_player.clone() {
-        (StatusCode::CONFLICT, "Player already exists").into_response()
-    } else {
-        ws.on_upgrade(move |mut socket| handler_on_upgrade(state, player_name, socket))
+    trace!("{} connected with {} (protocol {}, capabilities {:?})", player_name, client_name, protocol_version, capabilities);
+    check_protocol_version(protocol_version)?;
+    let welcome = welcome(state, player_name, protocol_version).await;
+    let encoding = subprotocol.unwrap_or_else(|| Encoding::from_capabilities(&capabilities));
+    socket
+        .send(encode_message(encoding, legacy, &welcome))
+        .await
+        .map_err(|err| (CLOSE_EXPECTED_HELLO, err.to_string()))?;
+    Ok(encoding)
+}
+async fn handler_on_upgrade(
+    state: Arc<AppState>,
+    player_name: String,
+    legacy: bool,
+    subprotocol: Option<Encoding>,
+    mut socket: WebSocket,
+) {
+    match handshake(&state, player_name.as_str(), legacy, subprotocol, &mut socket).await {
+        Err((code, reason)) => {
+            let _ = socket
+                .send(Message::Close(Some(CloseFrame {
+                    code,
+                    reason: Utf8Bytes::from(reason.as_str()),
+                })))
+                .await;
+        }
+        Ok(encoding) => {
+            let (writer, reader) = socket.split();
+            let writer = tokio::spawn(handler_writer(state.clone(), player_name.clone(), legacy, encoding, writer));
+            handler_reader(state.clone(), player_name.clone(), reader).await;
+            writer.abort();
+        }
     }
+    state
+        .game_state
+        .write()
+        .await
+        .disconnect_player(player_name)
+        .await
+        .unwrap_or(());
 }
-async fn handler_on_upgrade(state: Arc<AppState>, player_name: String, socket: WebSocket) {
-    let (writer, reader) = socket.split();
-    tokio::spawn(handler_reader(state.clone(), player_name.clone(), reader));
-    tokio::spawn(handler_writer(state, player_name, writer));
+/// WebSocket 和 HTTP 共用：快照请求直接回复，其余消息交给游戏主循环。
+/// 带回执通道时结果发到回执通道，否则推送给玩家
+async fn dispatch_player_message(
+    state: &Arc<AppState>,
+    player_name: &str,
+    msg: PlayerToServerMessage,
+    respond_to: Option<oneshot::Sender<ServerToPlayerMessage>>,
+) -> Result<(), ()> {
+    let game_state = state.game_state.read().await;
+    let player = game_state.players.get(player_name).ok_or(())?;
+    if let PlayerToServerMessage::RequestSnapshot = msg {
+        // 持有锁时放进队列，之后发布的增量版本一定比快照新
+        let snapshot = game_state.snapshot(Some(player_name), player.locale);
+        match respond_to {
+            Some(respond_to) => {
+                let _ = respond_to.send(snapshot);
+            }
+            None => player.push(player_name, snapshot),
+        }
+        Ok(())
+    } else {
+        let sender = player.from_channel.sender.clone();
+        drop(game_state);
+        sender.send(PlayerRequest { msg, respond_to }).await.map_err(|_| ())
+    }
 }
 async fn handler_reader(
     state: Arc<AppState>,
@@ -142,25 +347,29 @@ async fn handler_reader(
     mut reader: SplitStream<WebSocket>,
 ) {
     while let Some(Ok(msg)) = reader.next().await {
-        match msg {
-            Message::Text(msg) => { /* TODO: 完成消息处理 */ }
-            Message::Close(_) => {
-                state
-                    .game_state
-                    .write()
-                    .await
-                    .unregister_player(player_name.clone())
-                    .await
-                    .unwrap_or(());
-                break;
+        match decode_message(&msg) {
+            Some(msg) => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        trace!("Invalid message from {}: {}", player_name, err);
+                        continue;
+                    }
+                };
+                if dispatch_player_message(&state, player_name.as_str(), msg, None).await.is_err() {
+                    break;
+                }
             }
-            _ => {}
+            None if matches!(msg, Message::Close(_)) => break,
+            None => {}
         }
     }
 }
 async fn handler_writer(
     state: Arc<AppState>,
     player_name: String,
+    legacy: bool,
+    encoding: Encoding,
     mut writer: SplitSink<WebSocket, Message>,
 ) {
     let receiver = {
@@ -170,13 +379,268 @@ async fn handler_writer(
     };
 
     while let Some(msg) = { receiver.lock().await.recv().await.clone() } {
-        let send_result = writer
-            .send(Message::Text(Utf8Bytes::from(
-                serde_json::to_string(&msg).unwrap().as_str(),
-            )))
-            .await;
-        if let Err(_) = send_result {
+        let send_result = writer.send(encode_message(encoding, legacy, &msg)).await;
+        if send_result.is_err() {
             break;
         }
     }
 }
+
+/// 按观战配置注册观战者，成功时返回编号和延迟
+async fn join_spectator(state: &Arc<AppState>, locale: Locale) -> Result<(u32, Duration), Response> {
+    let (enable, max_spectators, delay_secs) = {
+        let cfg_temp = state.cfg.lock().await;
+        let spectator_cfg = &cfg_temp.server.spectator;
+        (spectator_cfg.enable, spectator_cfg.max_spectators, spectator_cfg.delay_secs)
+    };
+    if !enable {
+        return Err((StatusCode::FORBIDDEN, "Spectating is disabled").into_response());
+    }
+    let register_spectator = {
+        let mut state_temp = state.game_state.write().await;
+        let spectator = Spectator::new();
+        let _ = spectator
+            .to_channel
+            .sender
+            .send((Instant::now(), state_temp.snapshot(None, locale)));
+        state_temp.register_spectator(spectator, max_spectators).await
+    };
+    match register_spectator {
+        Err(_) => Err((StatusCode::SERVICE_UNAVAILABLE, "Too many spectators").into_response()),
+        Ok(id) => Ok((id, Duration::from_secs(delay_secs as u64))),
+    }
+}
+pub async fn spectate_handler(
+    State(state): State<Arc<AppState>>,
+    Query(args): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let locale = request_locale(&args, &headers);
+    match join_spectator(&state, locale).await {
+        Err(response) => response,
+        Ok((id, delay)) => {
+            let legacy = legacy_labels(&args);
+            // 观战者不发送 Hello，只能通过子协议选择编码
+            let ws = ws.protocols([JSON_SUBPROTOCOL, MSGPACK_SUBPROTOCOL]);
+            let encoding = selected_encoding(&ws).unwrap_or_default();
+            ws.on_upgrade(move |socket| spectator_on_upgrade(state, id, delay, legacy, encoding, socket))
+        }
+    }
+}
+async fn spectator_on_upgrade(
+    state: Arc<AppState>,
+    id: u32,
+    delay: Duration,
+    legacy: bool,
+    encoding: Encoding,
+    socket: WebSocket,
+) {
+    let (writer, mut reader) = socket.split();
+    let writer = tokio::spawn(spectator_writer(state.clone(), id, delay, legacy, encoding, writer));
+    // 观战者不能发送任何操作，只处理关闭
+    while let Some(Ok(msg)) = reader.next().await {
+        if let Message::Close(_) = msg {
+            break;
+        }
+    }
+    writer.abort();
+    state
+        .game_state
+        .write()
+        .await
+        .unregister_spectator(id)
+        .await
+        .unwrap_or(());
+}
+async fn spectator_writer(
+    state: Arc<AppState>,
+    id: u32,
+    delay: Duration,
+    legacy: bool,
+    encoding: Encoding,
+    mut writer: SplitSink<WebSocket, Message>,
+) {
+    let receiver = {
+        let game_state = state.game_state.read().await;
+        match game_state.spectators.get(&id) {
+            Some(spectator) => spectator.to_channel.receiver.clone(),
+            None => return,
+        }
+    };
+    while let Some((created_at, msg)) = { receiver.lock().await.recv().await } {
+        tokio::time::sleep_until(created_at + delay).await;
+        let send_result = writer.send(encode_message(encoding, legacy, &msg)).await;
+        if send_result.is_err() {
+            break;
+        }
+    }
+}
+
+/// SSE 连接断开时的清理，和 WebSocket 断开的处理一致
+enum Subscriber {
+    Player(String),
+    Spectator(u32),
+}
+struct EventsGuard {
+    state: Arc<AppState>,
+    subscriber: Subscriber,
+}
+impl Drop for EventsGuard {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        match &self.subscriber {
+            Subscriber::Player(player_name) => {
+                let player_name = player_name.clone();
+                tokio::spawn(async move {
+                    let _ = state.game_state.write().await.disconnect_player(player_name).await;
+                });
+            }
+            Subscriber::Spectator(id) => {
+                let id = *id;
+                tokio::spawn(async move {
+                    let _ = state.game_state.write().await.unregister_spectator(id).await;
+                });
+            }
+        }
+    }
+}
+fn sse_event(legacy: bool, msg: &ServerToPlayerMessage) -> Result<Event, Infallible> {
+    Ok(Event::default().data(with_legacy_labels(legacy, || serde_json::to_string(msg).unwrap())))
+}
+/// 给无法使用 WebSocket 的客户端的 SSE 推送。
+/// 不带 `player` 时和观战者收到的内容相同；带 `player` 时加入对局，
+/// 第一条事件是带会话令牌的 Welcome，之后通过 `POST /actions` 发送操作
+pub async fn events_handler(
+    State(state): State<Arc<AppState>>,
+    Query(args): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let locale = request_locale(&args, &headers);
+    let legacy = legacy_labels(&args);
+    let Some(player_name) = args.get("player").cloned() else {
+        let (id, delay) = match join_spectator(&state, locale).await {
+            Ok(x) => x,
+            Err(response) => return response,
+        };
+        let receiver = match state.game_state.read().await.spectators.get(&id) {
+            Some(spectator) => spectator.to_channel.receiver.clone(),
+            None => return StatusCode::GONE.into_response(),
+        };
+        let guard = EventsGuard {
+            state: state.clone(),
+            subscriber: Subscriber::Spectator(id),
+        };
+        let stream = futures::stream::unfold((receiver, guard), move |(receiver, guard)| async move {
+            let (created_at, msg) = receiver.lock().await.recv().await?;
+            tokio::time::sleep_until(created_at + delay).await;
+            Some((sse_event(legacy, &msg), (receiver, guard)))
+        });
+        return Sse::new(stream).keep_alive(KeepAlive::default()).into_response();
+    };
+    let protocol_version = args
+        .get("protocol_version")
+        .and_then(|x| x.parse::<u32>().ok())
+        .unwrap_or(PROTOCOL_VERSION);
+    if let Err((_, reason)) = check_protocol_version(protocol_version) {
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    if let Err(err) = join_player(&state, player_name.as_str(), join_session_token(&args, &headers), locale).await {
+        return err.into_response();
+    }
+    let welcome = welcome(&state, player_name.as_str(), protocol_version).await;
+    let receiver = match state.game_state.read().await.players.get(player_name.as_str()) {
+        Some(player) => player.to_channel.receiver.clone(),
+        None => return StatusCode::GONE.into_response(),
+    };
+    let guard = EventsGuard {
+        state: state.clone(),
+        subscriber: Subscriber::Player(player_name),
+    };
+    let messages = futures::stream::unfold((receiver, guard), move |(receiver, guard)| async move {
+        let msg = receiver.lock().await.recv().await?;
+        Some((sse_event(legacy, &msg), (receiver, guard)))
+    });
+    let stream = futures::stream::once(async move { sse_event(legacy, &welcome) }).chain(messages);
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+/// 配合 SSE 使用，用 Welcome 里的会话令牌识别玩家，处理方式和 WebSocket 收到的消息相同。
+/// 操作结果通过事件流推送
+pub async fn post_actions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(msg): Json<PlayerToServerMessage>,
+) -> impl IntoResponse {
+    let Some(session_token) = session_token(&headers) else {
+        return (StatusCode::UNAUTHORIZED, "Missing session token").into_response();
+    };
+    let player_name = {
+        let game_state = state.game_state.read().await;
+        game_state.player_by_session(session_token)
+    };
+    let Some(player_name) = player_name else {
+        return (StatusCode::FORBIDDEN, "Invalid session token").into_response();
+    };
+    match dispatch_player_message(&state, player_name, msg, None).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(()) => StatusCode::GONE.into_response(),
+    }
+}
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(SESSION_TOKEN_HEADER).and_then(|x| x.to_str().ok())
+}
+/// 等待游戏主循环处理 HTTP 操作的最长时间
+const ACTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// 同步的 HTTP 操作接口，消息同样进入玩家的消息队列，和 WebSocket 输入的顺序一致，
+/// 处理完后把 ActionAccepted / ActionRejected（或快照）放在响应里
+pub async fn post_player_action(
+    State(state): State<Arc<AppState>>,
+    Path(player_name): Path<String>,
+    Query(args): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    Json(msg): Json<PlayerToServerMessage>,
+) -> Response {
+    let Some(token) = session_token(&headers) else {
+        return (StatusCode::UNAUTHORIZED, "Missing session token").into_response();
+    };
+    let authorized = {
+        let game_state = state.game_state.read().await;
+        match game_state.players.get(player_name.as_str()) {
+            None => return (StatusCode::NOT_FOUND, "Player not exist").into_response(),
+            Some(player) => player.session_token.as_deref() == Some(token),
+        }
+    };
+    if !authorized {
+        return (StatusCode::FORBIDDEN, "Invalid session token").into_response();
+    }
+    let (respond_to, response) = oneshot::channel();
+    if dispatch_player_message(&state, player_name.as_str(), msg, Some(respond_to)).await.is_err() {
+        return StatusCode::GONE.into_response();
+    }
+    match tokio::time::timeout(ACTION_TIMEOUT, response).await {
+        Ok(Ok(message)) => {
+            let status = match message {
+                ServerToPlayerMessage::ActionRejected { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::OK,
+            };
+            (status, to_json(legacy_labels(&args), &message)).into_response()
+        }
+        _ => StatusCode::GATEWAY_TIMEOUT.into_response(),
+    }
+}
+/// 所有 HTTP 和 WebSocket 路由，都要经过 token 鉴权
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(root))
+        .route("/gamestate", get(get_game_state))
+        .route("/schema", get(get_schema))
+        .route("/playerinfo/{player_name}", get(get_player_info_with_path))
+        .route("/ws/{player_name}", any(ws_handler))
+        .route("/spectate", any(spectate_handler))
+        .route("/events", get(events_handler))
+        .route("/actions", post(post_actions))
+        .route("/players/{player_name}/actions", post(post_player_action))
+        .route("/playerinfo", get(get_player_info_with_query))
+        .route_layer(from_fn_with_state(state.clone(), auth_middleware))
+        .with_state(state)
+}
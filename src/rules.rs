@@ -0,0 +1,600 @@
+use crate::config::GameCfg;
+use crate::dtos::PlayerInfoResponse;
+use crate::enums::{
+    ActionOutcome, AttackTarget, BankAction, BankRecord, BankRecordKind, BidAction, Building, InvestmentAction, Items,
+    ServerBroadcastMessage, ServerToPlayerMessage,
+};
+use crate::{ActionError, GameState, Player};
+use rand::RngExt;
+use std::collections::HashMap;
+
+pub const PHASE_BID: u32 = 1;
+pub const PHASE_TAKE: u32 = 2;
+pub const PHASE_INVESTMENT: u32 = 3;
+pub const PHASE_SETTLEMENT: u32 = 4;
+
+impl Player {
+    pub fn item_count(&self, item: Items) -> u32 {
+        self.resources.get(&item).cloned().unwrap_or(0)
+    }
+    pub fn take_items(&mut self, item: Items, amount: u32) -> Result<(), ActionError> {
+        let count = self.item_count(item);
+        if count < amount {
+            return Err(ActionError::NotEnoughItems(item));
+        }
+        self.resources.insert(item, count - amount);
+        Ok(())
+    }
+    pub fn give_items(&mut self, item: Items, amount: u32) {
+        let count = self.resources.entry(item).or_insert(0);
+        *count = count.saturating_add(amount);
+    }
+    /// 停工的建筑不计入数量
+    pub fn building_count(&self, building: Building) -> u32 {
+        let owned = self.buildings.iter().filter(|x| **x == building).count();
+        let shut_down = self.shut_down_buildings.iter().filter(|x| **x == building).count();
+        owned.saturating_sub(shut_down) as u32
+    }
+    pub fn spend_ap(&mut self, amount: u32) -> Result<(), ActionError> {
+        if self.action_points < amount {
+            return Err(ActionError::NotEnoughAp(amount, self.action_points));
+        }
+        self.action_points -= amount;
+        Ok(())
+    }
+}
+impl GameState {
+    fn player_mut(&mut self, player_name: &str) -> &mut Player {
+        self.players.get_mut(player_name).unwrap()
+    }
+    /// 拿取阶段当前玩家结束回合，轮到下一个出价的玩家
+    pub fn end_take_turn(&mut self) {
+        if !self.waiting_on.is_empty() {
+            self.waiting_on.remove(0);
+        }
+        self.taken_this_turn = 0;
+    }
+}
+pub fn apply_bid(
+    game_state: &mut GameState,
+    cfg: &GameCfg,
+    player_name: &'static str,
+    action: BidAction,
+) -> Result<ActionOutcome, ActionError> {
+    match action {
+        BidAction::PlaceBid(amount) => {
+            if game_state.phase != PHASE_BID {
+                return Err(ActionError::WrongPhase);
+            }
+            if !game_state.waiting_on.contains(&player_name) {
+                return Err(ActionError::AlreadyDone);
+            }
+            game_state.player_mut(player_name).take_items(Items::Gold, amount)?;
+            game_state.bids.insert(player_name, amount);
+            game_state.waiting_on.retain(|x| *x != player_name);
+            Ok(ActionOutcome::Done)
+        }
+        BidAction::TakeItem(index) => {
+            if game_state.phase != PHASE_TAKE {
+                return Err(ActionError::WrongPhase);
+            }
+            if game_state.waiting_on.first() != Some(&player_name) {
+                return Err(ActionError::NotYourTurn);
+            }
+            if index as usize >= game_state.market.len() {
+                return Err(ActionError::NoSuchMarketItem(index));
+            }
+            let item = game_state.market.remove(index as usize);
+            game_state.player_mut(player_name).give_items(item, 1);
+            game_state.taken_this_turn += 1;
+            if game_state.taken_this_turn >= cfg.game_rules.bid.take_per_turn || game_state.market.is_empty() {
+                game_state.end_take_turn();
+            }
+            Ok(ActionOutcome::Done)
+        }
+        BidAction::EndTake => {
+            if game_state.phase != PHASE_TAKE {
+                return Err(ActionError::WrongPhase);
+            }
+            if game_state.waiting_on.first() != Some(&player_name) {
+                return Err(ActionError::NotYourTurn);
+            }
+            game_state.end_take_turn();
+            Ok(ActionOutcome::Done)
+        }
+    }
+}
+pub async fn apply_investment(
+    game_state: &mut GameState,
+    cfg: &GameCfg,
+    player_name: &'static str,
+    action: InvestmentAction,
+) -> Result<ActionOutcome, ActionError> {
+    if !cfg.game_rules.investment.enable {
+        return Err(ActionError::InvestmentDisabled);
+    }
+    if game_state.phase != PHASE_INVESTMENT {
+        return Err(ActionError::WrongPhase);
+    }
+    if !game_state.waiting_on.contains(&player_name) {
+        return Err(ActionError::AlreadyDone);
+    }
+    match action {
+        InvestmentAction::End => {
+            game_state.waiting_on.retain(|x| *x != player_name);
+            Ok(ActionOutcome::Done)
+        }
+        InvestmentAction::Build(building) => build(game_state, cfg, player_name, building),
+        InvestmentAction::Exchange { from, to, amount } => exchange(game_state, cfg, player_name, from, to, amount),
+        InvestmentAction::Explore => explore(game_state, cfg, player_name),
+        InvestmentAction::Pick { item } => pick(game_state, cfg, player_name, item),
+        InvestmentAction::Ore => dig_ore(game_state, cfg, player_name),
+        InvestmentAction::Bank(action) => bank(game_state, cfg, player_name, action),
+        InvestmentAction::Attack { target, aim } => attack(game_state, cfg, player_name, target.as_str(), aim).await,
+        InvestmentAction::Upgrade(building) => upgrade(game_state, cfg, player_name, building),
+        InvestmentAction::Mine { into, amount } => mine(game_state, cfg, player_name, into, amount),
+    }
+}
+/// 按当前市场价格把一种资源换成另一种，扣除手续费后向下取整，
+/// 只消耗换到这些目标资源实际需要的来源资源
+fn exchange(
+    game_state: &mut GameState,
+    cfg: &GameCfg,
+    player_name: &'static str,
+    from: Items,
+    to: Items,
+    amount: u32,
+) -> Result<ActionOutcome, ActionError> {
+    let exchange_cfg = &cfg.game_rules.investment.exchange;
+    let fee_percent = exchange_cfg.fee_percent.min(100);
+    let from_price = game_state.resource_values.get(&from).cloned().unwrap_or(0) as u128;
+    let to_price = game_state.resource_values.get(&to).cloned().unwrap_or(0) as u128;
+    if from == to {
+        return Err(ActionError::ExchangeSameItem(from));
+    }
+    if to_price == 0 {
+        return Err(ActionError::ExchangeTooSmall(to));
+    }
+    let player = game_state.player_mut(player_name);
+    if player.item_count(from) < amount {
+        return Err(ActionError::NotEnoughItems(from));
+    }
+    let net_percent = 100 - fee_percent as u128;
+    let to_amount = u32::try_from(amount as u128 * from_price * net_percent / (100 * to_price))
+        .map_err(|_| ActionError::AmountTooLarge(amount))?;
+    if to_amount == 0 {
+        return Err(ActionError::ExchangeTooSmall(to));
+    }
+    // 向上取整后不会超过请求的数量，额度按实际消耗的数量计算
+    let from_amount = (to_amount as u128 * to_price * 100).div_ceil(from_price * net_percent) as u32;
+    let exchanged = player.exchanged_this_epoch.saturating_add(from_amount);
+    if exchange_cfg.max_per_epoch > 0 && exchanged > exchange_cfg.max_per_epoch {
+        return Err(ActionError::ExchangeLimit(
+            exchange_cfg.max_per_epoch.saturating_sub(player.exchanged_this_epoch),
+        ));
+    }
+    player.spend_ap(cfg.game_rules.investment.needs_ap.exchange)?;
+    player.take_items(from, from_amount)?;
+    player.give_items(to, to_amount);
+    player.exchanged_this_epoch = exchanged;
+    Ok(ActionOutcome::Exchanged {
+        from,
+        from_amount,
+        to,
+        to_amount,
+        fee_percent,
+    })
+}
+/// 用对局的随机数生成器按权重抽取一个探索事件并结算到玩家身上
+fn explore(game_state: &mut GameState, cfg: &GameCfg, player_name: &'static str) -> Result<ActionOutcome, ActionError> {
+    let events = &cfg.game_rules.investment.explore.events;
+    let total: u32 = events.iter().map(|x| x.weight).sum();
+    if total == 0 {
+        return Err(ActionError::NoExploreEvents);
+    }
+    game_state.player_mut(player_name).spend_ap(cfg.game_rules.investment.needs_ap.explore)?;
+    let mut roll = game_state.rng.random_range(0..total);
+    let event = events
+        .iter()
+        .find(|x| {
+            if roll < x.weight {
+                true
+            } else {
+                roll -= x.weight;
+                false
+            }
+        })
+        .unwrap();
+    let player = game_state.player_mut(player_name);
+    let mut gained = HashMap::new();
+    let mut lost = HashMap::new();
+    for (&item, &amount) in event.gain.iter() {
+        player.give_items(item, amount);
+        gained.insert(item, amount);
+    }
+    for (&item, &amount) in event.lose.iter() {
+        let amount = amount.min(player.item_count(item));
+        player.take_items(item, amount)?;
+        lost.insert(item, amount);
+    }
+    Ok(ActionOutcome::Explored {
+        event: event.id.clone(),
+        gained,
+        lost,
+    })
+}
+fn produced(consumed: &[(Items, u32)], produced: &[(Items, u32)]) -> ActionOutcome {
+    ActionOutcome::Produced {
+        consumed: consumed.iter().cloned().collect(),
+        produced: produced.iter().cloned().collect(),
+    }
+}
+fn pick(game_state: &mut GameState, cfg: &GameCfg, player_name: &'static str, item: Items) -> Result<ActionOutcome, ActionError> {
+    let production = &cfg.game_rules.investment.production;
+    let mut amount = *production
+        .pick
+        .get(&item)
+        .ok_or(ActionError::CannotPick(item))?;
+    let player = game_state.player_mut(player_name);
+    if item == Items::Food {
+        for (&building, &bonus) in production.farm_food_bonus.iter() {
+            amount += bonus * player.building_count(building);
+        }
+    }
+    player.spend_ap(cfg.game_rules.investment.needs_ap.pick)?;
+    player.give_items(item, amount);
+    Ok(produced(&[], &[(item, amount)]))
+}
+fn dig_ore(game_state: &mut GameState, cfg: &GameCfg, player_name: &'static str) -> Result<ActionOutcome, ActionError> {
+    let production = &cfg.game_rules.investment.production;
+    let player = game_state.player_mut(player_name);
+    let mut amount = production.ore;
+    if player.building_count(Building::Miner) + player.building_count(Building::SuperMiner) > 0 {
+        amount += production.miner_ore_bonus;
+    }
+    player.spend_ap(cfg.game_rules.investment.needs_ap.ore)?;
+    player.give_items(Items::Ore, amount);
+    Ok(produced(&[], &[(Items::Ore, amount)]))
+}
+/// 冶炼需要矿机，高级矿机按倍数提高产量，消耗的矿石不变
+fn mine(
+    game_state: &mut GameState,
+    cfg: &GameCfg,
+    player_name: &'static str,
+    into: Items,
+    amount: u32,
+) -> Result<ActionOutcome, ActionError> {
+    let production = &cfg.game_rules.investment.production;
+    let ore_cost = *production
+        .mine_ore_cost
+        .get(&into)
+        .ok_or(ActionError::CannotMine(into))?;
+    let player = game_state.player_mut(player_name);
+    let super_miner = player.building_count(Building::SuperMiner) > 0;
+    if !super_miner && player.building_count(Building::Miner) == 0 {
+        return Err(ActionError::MissingBuilding(Building::Miner));
+    }
+    // 数量来自客户端，乘法溢出时直接拒绝
+    let consumed = ore_cost.checked_mul(amount).ok_or(ActionError::AmountTooLarge(amount))?;
+    if player.item_count(Items::Ore) < consumed {
+        return Err(ActionError::NotEnoughItems(Items::Ore));
+    }
+    let output = if super_miner {
+        amount
+            .checked_mul(production.super_miner_multiplier)
+            .ok_or(ActionError::AmountTooLarge(amount))?
+    } else {
+        amount
+    };
+    player.spend_ap(cfg.game_rules.investment.needs_ap.mine)?;
+    player.take_items(Items::Ore, consumed)?;
+    player.give_items(into, output);
+    Ok(produced(&[(Items::Ore, consumed)], &[(into, output)]))
+}
+fn bank(game_state: &mut GameState, cfg: &GameCfg, player_name: &'static str, action: BankAction) -> Result<ActionOutcome, ActionError> {
+    let epoch = game_state.epoch;
+    let player = game_state.player_mut(player_name);
+    let (kind, amount) = match action {
+        BankAction::Deposit(amount) => {
+            if player.item_count(Items::Gold) < amount {
+                return Err(ActionError::NotEnoughItems(Items::Gold));
+            }
+            player.spend_ap(cfg.game_rules.investment.needs_ap.bank)?;
+            player.take_items(Items::Gold, amount)?;
+            player.bank_money += amount;
+            (BankRecordKind::Deposit, amount as i64)
+        }
+        BankAction::Withdraw(amount) => {
+            if player.bank_money < amount {
+                return Err(ActionError::NotEnoughBankMoney(player.bank_money));
+            }
+            player.spend_ap(cfg.game_rules.investment.needs_ap.bank)?;
+            player.bank_money -= amount;
+            player.give_items(Items::Gold, amount);
+            (BankRecordKind::Withdraw, -(amount as i64))
+        }
+    };
+    player.bank_history.push(BankRecord {
+        epoch,
+        kind,
+        amount,
+        balance: player.bank_money,
+    });
+    Ok(ActionOutcome::Done)
+}
+fn build(game_state: &mut GameState, cfg: &GameCfg, player_name: &'static str, building: Building) -> Result<ActionOutcome, ActionError> {
+    let def = cfg
+        .game_rules
+        .buildings
+        .get(building)
+        .filter(|x| x.buildable)
+        .ok_or(ActionError::CannotBuild(building))?;
+    let player = game_state.player_mut(player_name);
+    let owned = player.buildings.iter().filter(|x| **x == building).count() as u32;
+    if def.max_per_player > 0 && owned >= def.max_per_player {
+        return Err(ActionError::BuildingLimit(building));
+    }
+    if let Some((item, _)) = def.cost.iter().find(|(x, y)| player.item_count(**x) < **y) {
+        return Err(ActionError::NotEnoughItems(*item));
+    }
+    player.spend_ap(def.ap)?;
+    for (&item, &amount) in def.cost.iter() {
+        player.take_items(item, amount)?;
+    }
+    player.buildings.push(building);
+    Ok(ActionOutcome::Done)
+}
+/// 按配置的升级路径把玩家的一座建筑原地替换成升级后的建筑
+fn upgrade(game_state: &mut GameState, cfg: &GameCfg, player_name: &'static str, building: Building) -> Result<ActionOutcome, ActionError> {
+    let path = cfg
+        .game_rules
+        .upgrades
+        .path_from(building)
+        .ok_or(ActionError::CannotUpgrade(building))?;
+    let player = game_state.player_mut(player_name);
+    let index = player
+        .buildings
+        .iter()
+        .position(|x| *x == building)
+        .ok_or(ActionError::MissingBuilding(building))?;
+    if let Some(missing) = path.requires.iter().find(|x| player.building_count(**x) == 0) {
+        return Err(ActionError::MissingBuilding(*missing));
+    }
+    // 升级和建造一样受目标建筑的数量上限约束
+    let max_per_player = cfg.game_rules.buildings.get(path.to).map_or(0, |x| x.max_per_player);
+    if max_per_player > 0 && player.building_count(path.to) >= max_per_player {
+        return Err(ActionError::BuildingLimit(path.to));
+    }
+    if let Some((item, _)) = path.cost.iter().find(|(x, y)| player.item_count(**x) < **y) {
+        return Err(ActionError::NotEnoughItems(*item));
+    }
+    player.spend_ap(path.ap)?;
+    for (&item, &amount) in path.cost.iter() {
+        player.take_items(item, amount)?;
+    }
+    player.buildings[index] = path.to;
+    Ok(ActionOutcome::Upgraded {
+        from: path.from,
+        to: path.to,
+    })
+}
+/// 炮台攻击：检查都通过后才支付行动点和铁，再按命中率结算，结果向所有人广播
+async fn attack(
+    game_state: &mut GameState,
+    cfg: &GameCfg,
+    player_name: &'static str,
+    target: &str,
+    aim: AttackTarget,
+) -> Result<ActionOutcome, ActionError> {
+    let combat = &cfg.game_rules.combat;
+    if !combat.enable {
+        return Err(ActionError::CombatDisabled);
+    }
+    let target = game_state
+        .players
+        .get_key_value(target)
+        .map(|(x, _)| *x)
+        .ok_or_else(|| ActionError::NoSuchPlayer(target.to_string()))?;
+    if target == player_name {
+        return Err(ActionError::AttackSelf);
+    }
+    let player = game_state.player_mut(player_name);
+    if player.building_count(Building::Cannon) == 0 {
+        return Err(ActionError::MissingBuilding(Building::Cannon));
+    }
+    if player.attacks_this_epoch >= combat.max_attacks_per_epoch {
+        return Err(ActionError::AttackLimit);
+    }
+    if player.item_count(Items::Iron) < combat.iron_cost {
+        return Err(ActionError::NotEnoughItems(Items::Iron));
+    }
+    if player.action_points < combat.ap_cost {
+        return Err(ActionError::NotEnoughAp(combat.ap_cost, player.action_points));
+    }
+    // 所有检查都通过后才扣行动点和铁，瞄准对方没有的建筑不会白白浪费
+    if let AttackTarget::Building(building) = aim
+        && !game_state.players[target].buildings.contains(&building)
+    {
+        return Err(ActionError::TargetMissingBuilding(target.to_string(), building));
+    }
+    let player = game_state.player_mut(player_name);
+    player.spend_ap(combat.ap_cost)?;
+    player.take_items(Items::Iron, combat.iron_cost)?;
+    player.attacks_this_epoch += 1;
+    let defended = game_state.players[target].building_count(Building::Cannon) > 0;
+    let (hit_percent, damage) = match aim {
+        AttackTarget::Building(_) => (combat.building_hit_percent, combat.building_damage),
+        AttackTarget::Stockpile(_) => (combat.stockpile_hit_percent, combat.stockpile_damage),
+    };
+    let damage = if defended { damage / 2 } else { damage };
+    let hit = game_state.rng.random_range(0..100) < hit_percent;
+    let epoch = game_state.epoch;
+    let victim = game_state.player_mut(target);
+    let destroyed = match aim {
+        _ if !hit => 0,
+        AttackTarget::Building(building) => {
+            let destroyed = damage.min(victim.buildings.iter().filter(|x| **x == building).count() as u32);
+            for _ in 0..destroyed {
+                let index = victim.buildings.iter().position(|x| *x == building).unwrap();
+                victim.buildings.remove(index);
+            }
+            destroyed
+        }
+        AttackTarget::Stockpile(item) => {
+            let from_stockpile = damage.min(victim.item_count(item));
+            victim.take_items(item, from_stockpile)?;
+            let from_bank = if item == Items::Gold && !cfg.game_rules.bank.safe_from_losses {
+                (damage - from_stockpile).min(victim.bank_money)
+            } else {
+                0
+            };
+            if from_bank > 0 {
+                victim.bank_money -= from_bank;
+                victim.bank_history.push(BankRecord {
+                    epoch,
+                    kind: BankRecordKind::Attacked,
+                    amount: -(from_bank as i64),
+                    balance: victim.bank_money,
+                });
+            }
+            from_stockpile + from_bank
+        }
+    };
+    game_state
+        .broadcast(ServerBroadcastMessage::Attack {
+            attacker: player_name.into(),
+            target: target.into(),
+            aim,
+            hit,
+            destroyed,
+        })
+        .await;
+    Ok(ActionOutcome::Attacked { hit, destroyed })
+}
+/// 存款利息向下取整，拥有银行建筑的玩家使用更高的利率
+fn pay_interest(game_state: &mut GameState, cfg: &GameCfg) {
+    let bank_cfg = &cfg.game_rules.bank;
+    let epoch = game_state.epoch;
+    for player in game_state.players.values_mut() {
+        let percent = if player.building_count(Building::Bank) > 0 {
+            bank_cfg.bank_building_interest_percent
+        } else {
+            bank_cfg.interest_percent
+        };
+        let interest = player.bank_money * percent / 100;
+        if interest == 0 {
+            continue;
+        }
+        player.bank_money += interest;
+        player.bank_history.push(BankRecord {
+            epoch,
+            kind: BankRecordKind::Interest,
+            amount: interest as i64,
+            balance: player.bank_money,
+        });
+    }
+}
+/// 每座正在运转的建筑按定义产出资源，停工的建筑不产出
+fn produce(game_state: &mut GameState, cfg: &GameCfg) {
+    for player in game_state.players.values_mut() {
+        let mut active = player.buildings.clone();
+        for building in player.shut_down_buildings.iter() {
+            if let Some(index) = active.iter().position(|x| x == building) {
+                active.remove(index);
+            }
+        }
+        for building in active {
+            if let Some(def) = cfg.game_rules.buildings.get(building) {
+                for (&item, &amount) in def.production.iter() {
+                    player.give_items(item, amount);
+                }
+            }
+        }
+    }
+}
+/// 每轮结束时按建筑数量支付食物维护费，付不起的玩家付光所有食物并受到惩罚。
+/// 上一轮停工的建筑在这里恢复，惩罚导致的停工持续到下一次结算
+async fn pay_upkeep(game_state: &mut GameState, cfg: &GameCfg) {
+    let upkeep = &cfg.game_rules.upkeep;
+    if !upkeep.enable {
+        return;
+    }
+    let mut starved = Vec::new();
+    for (&name, player) in game_state.players.iter_mut() {
+        player.shut_down_buildings.clear();
+        let amount = upkeep.flat
+            + player
+                .buildings
+                .iter()
+                .filter_map(|x| cfg.game_rules.buildings.get(*x))
+                .map(|x| x.upkeep)
+                .sum::<u32>();
+        let food = player.item_count(Items::Food);
+        if food >= amount {
+            player.resources.insert(Items::Food, food - amount);
+            continue;
+        }
+        player.resources.insert(Items::Food, 0);
+        let penalty = &upkeep.starvation;
+        player.ap_penalty += penalty.lose_ap;
+        player.penalty_points += penalty.penalty_points;
+        let shut_down = (penalty.shut_down_buildings as usize).min(player.buildings.len());
+        player.shut_down_buildings = player.buildings[player.buildings.len() - shut_down..].to_vec();
+        starved.push((name, amount - food));
+    }
+    for (player, missing_food) in starved {
+        game_state
+            .broadcast(ServerBroadcastMessage::Starved { player: player.into(), missing_food })
+            .await;
+    }
+}
+/// 保留不超过上限的剩余行动点，再补充基础、建筑和最低出价奖励，最后扣除饥饿惩罚，
+/// 并把结果单独推送给每个玩家
+async fn refill_ap(game_state: &mut GameState, cfg: &GameCfg) {
+    let ap_cfg = &cfg.game_rules.ap;
+    let lowest_bid = game_state.bids.values().min().cloned();
+    let highest_bid = game_state.bids.values().max().cloned();
+    let mut updates = Vec::new();
+    for (&name, player) in game_state.players.iter_mut() {
+        let carried_over = player.action_points.min(ap_cfg.carry_over_cap);
+        let mut refill = ap_cfg.base_refill;
+        for (&building, &bonus) in ap_cfg.building_bonus.iter() {
+            refill += bonus * player.building_count(building);
+        }
+        let bid = game_state.bids.get(name).cloned();
+        if lowest_bid != highest_bid && bid.is_some() && bid == lowest_bid {
+            refill += ap_cfg.last_bid_bonus;
+        }
+        refill = refill.saturating_sub(player.ap_penalty);
+        player.ap_penalty = 0;
+        player.action_points = carried_over + refill;
+        updates.push((name, carried_over, refill, PlayerInfoResponse::from(&*player).localized(player.locale)));
+    }
+    for (name, carried_over, refill, info) in updates {
+        game_state
+            .send_to(
+                name,
+                ServerToPlayerMessage::PlayerStateUpdate {
+                    carried_over,
+                    refill,
+                    info,
+                },
+            )
+            .await;
+    }
+}
+/// 结算阶段：支付食物维护费，发放存款利息，从牌堆补充市场到配置的张数
+pub async fn settle_epoch(game_state: &mut GameState, cfg: &GameCfg) {
+    produce(game_state, cfg);
+    pay_upkeep(game_state, cfg).await;
+    pay_interest(game_state, cfg);
+    let missing = (cfg.game_rules.prepare.draw_cards as usize).saturating_sub(game_state.market.len());
+    let count = missing.min(game_state.current_deck.len());
+    let mut cards: Vec<Items> = game_state.current_deck.drain(0..count).collect();
+    game_state.market.append(&mut cards);
+    for player in game_state.players.values_mut() {
+        player.exchanged_this_epoch = 0;
+        player.attacks_this_epoch = 0;
+    }
+    refill_ap(game_state, cfg).await;
+}
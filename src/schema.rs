@@ -0,0 +1,107 @@
+use crate::PROTOCOL_VERSION;
+use crate::dtos::{GameStateResponse, PlayerInfoResponse};
+use crate::enums::{PlayerToServerMessage, ServerBroadcastMessage, ServerToPlayerMessage};
+use schemars::schema_for;
+use serde_json::{Value, json};
+
+/// 所有入站、出站消息和 HTTP 接口的 JSON Schema，客户端可以据此生成类型定义并在测试中比对。
+/// HTTP 接口按路由分成 `request`、`response` 和按状态码列出的 `errors`，没有请求体或响应体的一侧为 `null`。
+/// 大部分错误的响应体是纯文本的原因说明，鉴权失败时响应体为空
+pub fn schema() -> Value {
+    let text = json!({"type": "string", "contentMediaType": "text/plain"});
+    // 每个路由都要经过鉴权：没带 token 是 401，token 不对是 403
+    let auth = |errors: Value| {
+        let mut res = json!({"401": null, "403": null});
+        res.as_object_mut().unwrap().extend(errors.as_object().unwrap().clone());
+        res
+    };
+    // 请求体不是合法的消息时由 JSON 解析返回纯文本错误
+    let body_errors = json!({"400": text, "415": text, "422": text});
+    let session_errors = json!({"401": text, "403": text});
+    let mut post_actions_errors = body_errors.clone();
+    post_actions_errors.as_object_mut().unwrap().extend(session_errors.as_object().unwrap().clone());
+    post_actions_errors["410"] = Value::Null;
+    let mut player_action_errors = post_actions_errors.clone();
+    player_action_errors["404"] = text.clone();
+    // 操作被拒绝时返回 ActionRejected，请求体解析失败时仍是纯文本
+    player_action_errors["422"] = json!({"anyOf": [schema_for!(ServerToPlayerMessage), text]});
+    player_action_errors["504"] = Value::Null;
+    json!({
+        "protocol_version": PROTOCOL_VERSION,
+        "inbound": {
+            "PlayerToServerMessage": schema_for!(PlayerToServerMessage),
+        },
+        "outbound": {
+            "ServerToPlayerMessage": schema_for!(ServerToPlayerMessage),
+            "ServerBroadcastMessage": schema_for!(ServerBroadcastMessage),
+        },
+        // WebSocket 路由收发的就是上面的入站和出站消息，不在 `http` 里重复
+        "websocket": {
+            "/ws/{player_name}": {
+                "receives": "PlayerToServerMessage",
+                "sends": "ServerToPlayerMessage",
+            },
+            "/spectate": {
+                "receives": null,
+                "sends": "ServerToPlayerMessage",
+            },
+        },
+        "http": {
+            "GET /": {
+                "request": null,
+                "response": text,
+                "errors": auth(json!({})),
+            },
+            // 就是这份文档本身
+            "GET /schema": {
+                "request": null,
+                "response": {"type": "object"},
+                "errors": auth(json!({})),
+            },
+            "GET /gamestate": {
+                "request": null,
+                "response": schema_for!(GameStateResponse),
+                "errors": auth(json!({})),
+            },
+            // 缺少 `player` 参数或玩家不存在时返回字段为空的 PlayerInfoResponse
+            "GET /playerinfo": {
+                "request": null,
+                "response": schema_for!(PlayerInfoResponse),
+                "errors": auth(json!({
+                    "400": schema_for!(PlayerInfoResponse),
+                    "404": schema_for!(PlayerInfoResponse),
+                })),
+            },
+            "GET /playerinfo/{player_name}": {
+                "request": null,
+                "response": schema_for!(PlayerInfoResponse),
+                "errors": auth(json!({"404": schema_for!(PlayerInfoResponse)})),
+            },
+            // SSE 事件流，每条事件的 data 是一条 ServerToPlayerMessage
+            "GET /events": {
+                "request": null,
+                "response": schema_for!(ServerToPlayerMessage),
+                "errors": auth(json!({
+                    "400": text,
+                    "401": text,
+                    "403": text,
+                    "404": text,
+                    "409": text,
+                    "410": null,
+                    "503": text,
+                })),
+            },
+            // 返回空的 202，结果从事件流推送
+            "POST /actions": {
+                "request": schema_for!(PlayerToServerMessage),
+                "response": null,
+                "errors": auth(post_actions_errors),
+            },
+            "POST /players/{player_name}/actions": {
+                "request": schema_for!(PlayerToServerMessage),
+                "response": schema_for!(ServerToPlayerMessage),
+                "errors": auth(player_action_errors),
+            },
+        },
+    })
+}
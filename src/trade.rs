@@ -0,0 +1,141 @@
+use crate::config::GameCfg;
+use crate::dtos::TradeOfferResponse;
+use crate::enums::{ActionOutcome, Items, ServerBroadcastMessage, ServerToPlayerMessage, TradeAction};
+use crate::{ActionError, GameState, Player};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct TradeOffer {
+    pub id: u32,
+    pub from: &'static str,
+    pub to: &'static str,
+    pub give: HashMap<Items, u32>,
+    pub want: HashMap<Items, u32>,
+}
+fn check_items(player: &Player, items: &HashMap<Items, u32>) -> Result<(), ActionError> {
+    for (&item, &amount) in items.iter() {
+        if player.item_count(item) < amount {
+            return Err(ActionError::NotEnoughItems(item));
+        }
+    }
+    Ok(())
+}
+impl GameState {
+    fn create_trade(
+        &mut self,
+        from: &'static str,
+        to: &str,
+        give: HashMap<Items, u32>,
+        want: HashMap<Items, u32>,
+    ) -> Result<u32, ActionError> {
+        let to = self
+            .players
+            .get_key_value(to)
+            .map(|(x, _)| *x)
+            .ok_or_else(|| ActionError::NoSuchPlayer(to.to_string()))?;
+        if to == from {
+            return Err(ActionError::TradeWithSelf);
+        }
+        let give = give.into_iter().filter(|(_, y)| *y > 0).collect::<HashMap<Items, u32>>();
+        let want = want.into_iter().filter(|(_, y)| *y > 0).collect::<HashMap<Items, u32>>();
+        if give.is_empty() && want.is_empty() {
+            return Err(ActionError::EmptyTrade);
+        }
+        check_items(&self.players[from], &give)?;
+        let id = self.next_trade_id;
+        self.next_trade_id += 1;
+        self.trades.insert(
+            id,
+            TradeOffer {
+                id,
+                from,
+                to,
+                give,
+                want,
+            },
+        );
+        Ok(id)
+    }
+    fn trade_for(&self, id: u32, player_name: &str) -> Result<TradeOffer, ActionError> {
+        match self.trades.get(&id) {
+            Some(offer) if offer.to == player_name => Ok(offer.clone()),
+            _ => Err(ActionError::NoSuchTrade(id)),
+        }
+    }
+    fn take_trade_for(&mut self, id: u32, player_name: &str) -> Result<TradeOffer, ActionError> {
+        let offer = self.trade_for(id, player_name)?;
+        self.trades.remove(&id);
+        Ok(offer)
+    }
+    /// 在持有写锁的情况下先检查双方库存，再一次性交换，不会出现只交换一半的情况
+    fn execute_trade(&mut self, offer: &TradeOffer) -> Result<(), ActionError> {
+        check_items(&self.players[offer.from], &offer.give)?;
+        check_items(&self.players[offer.to], &offer.want)?;
+        for (&item, &amount) in offer.give.iter() {
+            self.players.get_mut(offer.from).unwrap().take_items(item, amount)?;
+            self.players.get_mut(offer.to).unwrap().give_items(item, amount);
+        }
+        for (&item, &amount) in offer.want.iter() {
+            self.players.get_mut(offer.to).unwrap().take_items(item, amount)?;
+            self.players.get_mut(offer.from).unwrap().give_items(item, amount);
+        }
+        Ok(())
+    }
+}
+pub async fn apply_trade(
+    game_state: &mut GameState,
+    cfg: &GameCfg,
+    player_name: &'static str,
+    action: TradeAction,
+) -> Result<ActionOutcome, ActionError> {
+    if !cfg.game_rules.trade.enable {
+        return Err(ActionError::TradeDisabled);
+    }
+    if game_state.phase != cfg.game_rules.trade.phase {
+        return Err(ActionError::WrongPhase);
+    }
+    match action {
+        TradeAction::Offer { to, give, want } => {
+            let id = game_state.create_trade(player_name, to.as_str(), give, want)?;
+            let offer = TradeOfferResponse::from(&game_state.trades[&id]);
+            game_state
+                .send_to(game_state.trades[&id].to, ServerToPlayerMessage::TradeOffered { offer })
+                .await;
+            Ok(ActionOutcome::TradeProposed { id })
+        }
+        TradeAction::Accept { id } => {
+            // 交换失败时报价保留，库存够了可以再接受，或者拒绝
+            let offer = game_state.trade_for(id, player_name)?;
+            game_state.execute_trade(&offer)?;
+            game_state.trades.remove(&id);
+            game_state
+                .broadcast(ServerBroadcastMessage::TradeCompleted {
+                    offer: TradeOfferResponse::from(&offer),
+                })
+                .await;
+            Ok(ActionOutcome::Done)
+        }
+        TradeAction::Reject { id } => {
+            let offer = game_state.take_trade_for(id, player_name)?;
+            game_state
+                .send_to(offer.from, ServerToPlayerMessage::TradeRejected { id, by: player_name.into() })
+                .await;
+            Ok(ActionOutcome::Done)
+        }
+        TradeAction::Counter { id, give, want } => {
+            // 还价通过检查后才撤掉原报价，无效的还价不会让原报价丢失
+            let offer = game_state.trade_for(id, player_name)?;
+            let counter_id = game_state.create_trade(player_name, offer.from, give, want)?;
+            game_state.trades.remove(&id);
+            game_state
+                .send_to(offer.from, ServerToPlayerMessage::TradeRejected { id, by: player_name.into() })
+                .await;
+            let id = counter_id;
+            let counter = TradeOfferResponse::from(&game_state.trades[&id]);
+            game_state
+                .send_to(offer.from, ServerToPlayerMessage::TradeOffered { offer: counter })
+                .await;
+            Ok(ActionOutcome::TradeProposed { id })
+        }
+    }
+}
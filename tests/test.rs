@@ -1,7 +1,1022 @@
 #[cfg(test)]
 mod tests {
+    use resource_island_server::codec::{self, Encoding, Frame};
+    use resource_island_server::config::{BuildingCatalogue, ExploreEvent, GameCfg, ItemCatalogue};
+    use resource_island_server::diff::PublishedState;
+    use resource_island_server::game::{apply_timeouts, handle_game_message};
+    use resource_island_server::dtos::GameStateResponse;
+    use resource_island_server::enums::{with_legacy_labels, ActionOutcome, AttackTarget, BankAction, BankRecordKind, BidAction, Building, InvestmentAction, Items, PlayerToServerMessage, ServerBroadcastMessage, ServerToPlayerMessage, StateDiff, TradeAction};
+    use resource_island_server::rules::{apply_bid, apply_investment, settle_epoch, PHASE_BID, PHASE_INVESTMENT, PHASE_TAKE};
+    use resource_island_server::locale::{self, Locale};
+    use resource_island_server::routes::router;
+    use resource_island_server::trade::apply_trade;
+    use resource_island_server::{ActionError, AppState, GameState, JoinError, Player, PlayerRequest, Spectator};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
     #[test]
     fn test_get_game_state(){
         
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_state_diff_only_reports_changes() {
+        let mut game_state = GameState::new();
+        game_state.players.insert("a", Player::new());
+        let before = PublishedState::from(&game_state);
+        assert!(before.diff(&PublishedState::from(&game_state)).is_empty());
+        game_state.market.push(Items::Gold);
+        game_state.players.get_mut("a").unwrap().action_points = 3;
+        let diffs = before.diff(&PublishedState::from(&game_state));
+        assert_eq!(diffs.len(), 2);
+        assert!(diffs.iter().any(|x| matches!(x, StateDiff::Market { .. })));
+        assert!(diffs.iter().any(|x| matches!(x, StateDiff::ActionPoints { action_points: 3, .. })));
+    }
+    #[tokio::test]
+    async fn test_full_player_channel_does_not_block() {
+        let mut game_state = GameState::new();
+        game_state.players.insert("a", Player::new());
+        let flood = async {
+            for _ in 0..300 {
+                game_state.send_to("a", ServerToPlayerMessage::ActionRejected { reason: String::new() }).await;
+            }
+        };
+        assert!(tokio::time::timeout(std::time::Duration::from_secs(1), flood).await.is_ok());
+    }
+    #[tokio::test]
+    async fn test_spectator_queue_keeps_every_delayed_message() {
+        let mut game_state = GameState::new();
+        let id = game_state.register_spectator(Spectator::new(), 1).await.unwrap();
+        for _ in 0..300 {
+            game_state.send_spectators(ServerToPlayerMessage::ActionRejected { reason: String::new() }).await;
+        }
+        let mut receiver = game_state.spectators[&id].to_channel.receiver.lock().await;
+        assert_eq!((0..).take_while(|_| receiver.try_recv().is_ok()).count(), 300);
+    }
+    #[tokio::test]
+    async fn test_reconnect_requires_session_token() {
+        let mut game_state = GameState::new();
+        game_state.register_player("a".to_string(), Player::new()).await.unwrap();
+        game_state.started = true;
+        game_state.players.get_mut("a").unwrap().session_token = Some("secret".to_string());
+        assert!(matches!(game_state.reconnect_player("b", Some("secret"), Locale::Zh).await, Err(JoinError::NoSuchPlayer(_))));
+        assert!(matches!(game_state.reconnect_player("a", None, Locale::Zh).await, Err(JoinError::MissingSessionToken)));
+        assert!(matches!(game_state.reconnect_player("a", Some("guess"), Locale::Zh).await, Err(JoinError::InvalidSessionToken)));
+        game_state.disconnect_player("a".to_string()).await.unwrap();
+        assert!(game_state.reconnect_player("a", Some("secret"), Locale::En).await.is_ok());
+        assert!(matches!(game_state.reconnect_player("a", Some("secret"), Locale::Zh).await, Err(JoinError::AlreadyConnected(_))));
+    }
+    #[test]
+    fn test_bid_then_take_in_bid_order() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        for name in ["a", "b"] {
+            let mut player = Player::new();
+            player.resources.insert(Items::Gold, 5);
+            game_state.players.insert(name, player);
+        }
+        game_state.phase = PHASE_BID;
+        game_state.waiting_on = vec!["a", "b"];
+        assert!(apply_bid(&mut game_state, &cfg, "a", BidAction::PlaceBid(6)).is_err());
+        assert!(apply_bid(&mut game_state, &cfg, "a", BidAction::PlaceBid(2)).is_ok());
+        assert!(apply_bid(&mut game_state, &cfg, "a", BidAction::PlaceBid(1)).is_err());
+        assert_eq!(game_state.players["a"].resources[&Items::Gold], 3);
+
+        game_state.phase = PHASE_TAKE;
+        game_state.market = vec![Items::Wood, Items::Diamond];
+        game_state.waiting_on = vec!["a", "b"];
+        assert!(apply_bid(&mut game_state, &cfg, "b", BidAction::TakeItem(0)).is_err());
+        assert!(apply_bid(&mut game_state, &cfg, "a", BidAction::TakeItem(1)).is_ok());
+        assert!(apply_bid(&mut game_state, &cfg, "a", BidAction::EndTake).is_ok());
+        assert_eq!(game_state.waiting_on, vec!["b"]);
+        assert_eq!(game_state.players["a"].resources[&Items::Diamond], 1);
+    }
+    #[tokio::test]
+    async fn test_exchange_uses_current_prices() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        game_state.resource_values = cfg.game_rules.items.values();
+        let mut player = Player::with_cfg(&cfg);
+        player.resources.insert(Items::Wood, 10);
+        game_state.players.insert("a", player);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a"];
+        let action = InvestmentAction::Exchange { from: Items::Wood, to: Items::Gold, amount: 10 };
+        let outcome = apply_investment(&mut game_state, &cfg, "a", action).await.unwrap();
+        // 10 木材价值 20，扣 10% 手续费后 18，可换 3 金币，实际只需要 10 木材
+        assert!(matches!(outcome, ActionOutcome::Exchanged { from_amount: 10, to_amount: 3, .. }));
+        assert_eq!(game_state.players["a"].resources[&Items::Gold], 3);
+        assert_eq!(game_state.players["a"].action_points, 3);
+    }
+    #[tokio::test]
+    async fn test_trade_offer_accept_swaps_both_sides() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        let mut a = Player::with_cfg(&cfg);
+        a.resources.insert(Items::Wood, 5);
+        game_state.players.insert("a", a);
+        let mut b = Player::with_cfg(&cfg);
+        b.resources.insert(Items::Gold, 3);
+        game_state.players.insert("b", b);
+        game_state.phase = cfg.game_rules.trade.phase;
+        let offer = |give: u32, want: u32| TradeAction::Offer {
+            to: "b".to_string(),
+            give: [(Items::Wood, give)].into(),
+            want: [(Items::Gold, want)].into(),
+        };
+
+        let too_much = apply_trade(&mut game_state, &cfg, "a", offer(6, 2)).await;
+        assert!(matches!(too_much, Err(ActionError::NotEnoughItems(Items::Wood))));
+        let Ok(ActionOutcome::TradeProposed { id }) = apply_trade(&mut game_state, &cfg, "a", offer(4, 2)).await else {
+            panic!("offer should be accepted");
+        };
+        let received = game_state.players["b"].to_channel.receiver.lock().await.try_recv().unwrap();
+        assert!(matches!(received, ServerToPlayerMessage::TradeOffered { offer } if offer.id == id && offer.from == "a"));
+        // 只有被报价的一方可以接受
+        let wrong_side = apply_trade(&mut game_state, &cfg, "a", TradeAction::Accept { id }).await;
+        assert!(matches!(wrong_side, Err(ActionError::NoSuchTrade(_))));
+
+        assert!(apply_trade(&mut game_state, &cfg, "b", TradeAction::Accept { id }).await.is_ok());
+        assert_eq!(game_state.players["a"].resources[&Items::Wood], 1);
+        assert_eq!(game_state.players["a"].resources[&Items::Gold], 2);
+        assert_eq!(game_state.players["b"].resources[&Items::Wood], 4);
+        assert_eq!(game_state.players["b"].resources[&Items::Gold], 1);
+        let again = apply_trade(&mut game_state, &cfg, "b", TradeAction::Accept { id }).await;
+        assert!(matches!(again, Err(ActionError::NoSuchTrade(_))));
+
+        // 接受时对方已经不够的话整笔交易失败，双方库存不变
+        let Ok(ActionOutcome::TradeProposed { id }) = apply_trade(&mut game_state, &cfg, "b", TradeAction::Offer {
+            to: "a".to_string(),
+            give: [(Items::Gold, 1)].into(),
+            want: [(Items::Wood, 5)].into(),
+        }).await else {
+            panic!("offer should be accepted");
+        };
+        assert!(matches!(apply_trade(&mut game_state, &cfg, "a", TradeAction::Accept { id }).await, Err(ActionError::NotEnoughItems(Items::Wood))));
+        assert_eq!(game_state.players["a"].resources[&Items::Wood], 1);
+        assert_eq!(game_state.players["b"].resources[&Items::Gold], 1);
+        // 交换失败后报价还在，凑够库存可以再接受
+        game_state.players.get_mut("a").unwrap().resources.insert(Items::Wood, 5);
+        assert!(apply_trade(&mut game_state, &cfg, "a", TradeAction::Accept { id }).await.is_ok());
+        assert_eq!(game_state.players["a"].resources[&Items::Gold], 3);
+    }
+    #[tokio::test]
+    async fn test_invalid_counter_keeps_the_original_offer() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        let mut a = Player::with_cfg(&cfg);
+        a.resources.insert(Items::Wood, 5);
+        game_state.players.insert("a", a);
+        game_state.players.insert("b", Player::with_cfg(&cfg));
+        game_state.phase = cfg.game_rules.trade.phase;
+        let Ok(ActionOutcome::TradeProposed { id }) = apply_trade(&mut game_state, &cfg, "a", TradeAction::Offer {
+            to: "b".to_string(),
+            give: [(Items::Wood, 2)].into(),
+            want: [].into(),
+        }).await else {
+            panic!("offer should be accepted");
+        };
+        let counter = |give: u32| TradeAction::Counter { id, give: [(Items::Gold, give)].into(), want: [(Items::Wood, 3)].into() };
+        // b 没有金币，还价无效，原报价保留，a 也不会收到拒绝
+        assert!(matches!(apply_trade(&mut game_state, &cfg, "b", counter(1)).await, Err(ActionError::NotEnoughItems(Items::Gold))));
+        assert!(game_state.trades.contains_key(&id));
+        assert!(game_state.players["a"].to_channel.receiver.lock().await.try_recv().is_err());
+
+        let Ok(ActionOutcome::TradeProposed { id: counter_id }) = apply_trade(&mut game_state, &cfg, "b", counter(0)).await else {
+            panic!("counter should be accepted");
+        };
+        assert!(!game_state.trades.contains_key(&id));
+        let mut received = game_state.players["a"].to_channel.receiver.lock().await;
+        assert!(matches!(received.try_recv().unwrap(), ServerToPlayerMessage::TradeRejected { id: x, by } if x == id && by == "b"));
+        assert!(matches!(received.try_recv().unwrap(), ServerToPlayerMessage::TradeOffered { offer } if offer.id == counter_id));
+    }
+    #[tokio::test]
+    async fn test_exchange_limits_count_consumed_amount() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        game_state.resource_values = cfg.game_rules.items.values();
+        let mut player = Player::with_cfg(&cfg);
+        player.resources.insert(Items::Wood, 10);
+        player.action_points = 10;
+        game_state.players.insert("a", player);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a"];
+        let same = InvestmentAction::Exchange { from: Items::Wood, to: Items::Wood, amount: 1 };
+        assert!(matches!(apply_investment(&mut game_state, &cfg, "a", same).await, Err(ActionError::ExchangeSameItem(_))));
+        let huge = InvestmentAction::Exchange { from: Items::Wood, to: Items::Gold, amount: u32::MAX };
+        assert!(matches!(apply_investment(&mut game_state, &cfg, "a", huge).await, Err(ActionError::NotEnoughItems(_))));
+        // 请求 8 个木材只消耗 7 个，剩余额度 3 还能把 3 个木材换成铁
+        let action = InvestmentAction::Exchange { from: Items::Wood, to: Items::Gold, amount: 8 };
+        assert!(apply_investment(&mut game_state, &cfg, "a", action).await.is_ok());
+        assert_eq!(game_state.players["a"].exchanged_this_epoch, 7);
+        let action = InvestmentAction::Exchange { from: Items::Wood, to: Items::Iron, amount: 3 };
+        assert!(apply_investment(&mut game_state, &cfg, "a", action).await.is_ok());
+        assert_eq!(game_state.players["a"].exchanged_this_epoch, 10);
+    }
+    #[tokio::test]
+    async fn test_explore_applies_weighted_event_and_caps_losses() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.investment.explore.events = vec![
+            ExploreEvent::new("storm", "风暴", "Storm", 1, &[(Items::Wood, 2)], &[(Items::Food, 10)]),
+            ExploreEvent::new("never", "不会发生", "Never", 0, &[(Items::Diamond, 9)], &[]),
+        ];
+        let mut game_state = GameState::new();
+        let mut player = Player::with_cfg(&cfg);
+        player.resources.insert(Items::Food, 5);
+        let ap = player.action_points;
+        game_state.players.insert("a", player);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a"];
+        let outcome = apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Explore).await.unwrap();
+        // 权重为 0 的事件不会被抽到，不够扣的食物只扣到 0
+        assert!(matches!(&outcome, ActionOutcome::Explored { event, gained, lost } if event == "storm" && gained[&Items::Wood] == 2 && lost[&Items::Food] == 5));
+        let player = &game_state.players["a"];
+        assert_eq!(player.resources[&Items::Food], 0);
+        assert_eq!(player.resources[&Items::Diamond], 0);
+        assert_eq!(player.action_points, ap - cfg.game_rules.investment.needs_ap.explore);
+
+        // 事件名称和物品一样按语言本地化
+        cfg.register_names();
+        assert_eq!(locale::display_name("storm", Locale::En), Some("Storm"));
+        assert_eq!(locale::display_name("storm", Locale::Zh), Some("风暴"));
+
+        cfg.game_rules.investment.explore.events.clear();
+        let empty = apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Explore).await;
+        assert!(matches!(empty, Err(ActionError::NoExploreEvents)));
+    }
+    #[tokio::test]
+    async fn test_bank_deposit_withdraw_and_interest() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        let mut player = Player::with_cfg(&cfg);
+        player.resources.insert(Items::Gold, 30);
+        game_state.players.insert("a", player);
+        let mut banker = Player::with_cfg(&cfg);
+        banker.buildings = vec![Building::Bank];
+        banker.bank_money = 20;
+        game_state.players.insert("b", banker);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a", "b"];
+        let bank = |action| InvestmentAction::Bank(action);
+        assert!(apply_investment(&mut game_state, &cfg, "a", bank(BankAction::Deposit(25))).await.is_ok());
+        let overdrawn = apply_investment(&mut game_state, &cfg, "a", bank(BankAction::Withdraw(30))).await;
+        assert!(matches!(overdrawn, Err(ActionError::NotEnoughBankMoney(25))));
+        assert!(apply_investment(&mut game_state, &cfg, "a", bank(BankAction::Withdraw(5))).await.is_ok());
+        assert_eq!(game_state.players["a"].resources[&Items::Gold], 10);
+        assert_eq!(game_state.players["a"].bank_money, 20);
+
+        // 利息向下取整，有银行建筑的玩家用更高的利率
+        settle_epoch(&mut game_state, &cfg).await;
+        let bank_cfg = &cfg.game_rules.bank;
+        assert_eq!(game_state.players["a"].bank_money, 20 + 20 * bank_cfg.interest_percent / 100);
+        assert_eq!(game_state.players["b"].bank_money, 20 + 20 * bank_cfg.bank_building_interest_percent / 100);
+        let history = &game_state.players["a"].bank_history;
+        assert_eq!(history.len(), 3);
+        assert!(matches!(history[2].kind, BankRecordKind::Interest));
+        assert_eq!(history[2].balance, game_state.players["a"].bank_money);
+    }
+    #[test]
+    fn test_custom_item_from_catalogue() {
+        let mut value = serde_yaml::to_value(GameCfg::with_defaults()).unwrap();
+        let stone: serde_yaml::Value = serde_yaml::from_str(
+            "{id: stone, names: {zh: 石头, en: Stone}, value: 1, deck: 30, starting: 2}",
+        )
+        .unwrap();
+        value["game_rules"]["items"].as_sequence_mut().unwrap().push(stone);
+        ItemCatalogue::register_from_yaml(&value);
+        let cfg: GameCfg = serde_yaml::from_value(value).unwrap();
+        let stone = Items::from_id("stone").unwrap();
+        assert_eq!(<&'static str>::from(&stone), "石头");
+        assert_eq!(cfg.game_rules.items.deck()[&stone], 30);
+        assert_eq!(Player::with_cfg(&cfg).resources[&stone], 2);
+    }
+    #[test]
+    fn test_legacy_item_keys_are_migrated() {
+        let mut value = serde_yaml::to_value(GameCfg::with_defaults()).unwrap();
+        let rules = value["game_rules"].as_mapping_mut().unwrap();
+        rules.remove("items");
+        rules.insert("resource_values_default".into(), serde_yaml::from_str("{gold: 9, wood: 4}").unwrap());
+        value["game_rules"]["prepare"]["deck"] = serde_yaml::from_str("{diamond: 7}").unwrap();
+        value["game_rules"]["prepare"]["defaults_give_player"]["food"] = 11.into();
+        ItemCatalogue::migrate_legacy(&mut value).unwrap();
+        let cfg: GameCfg = serde_yaml::from_value(value.clone()).unwrap();
+        assert_eq!(cfg.game_rules.items.values()[&Items::Gold], 9);
+        assert_eq!(cfg.game_rules.items.values()[&Items::Iron], 2);
+        assert_eq!(cfg.game_rules.items.deck()[&Items::Diamond], 7);
+        assert_eq!(Player::with_cfg(&cfg).resources[&Items::Food], 11);
+
+        // 新旧格式同时出现时拒绝加载
+        value["game_rules"]["prepare"]["deck"] = serde_yaml::from_str("{diamond: 7}").unwrap();
+        assert!(ItemCatalogue::migrate_legacy(&mut value).is_err());
+    }
+    #[test]
+    fn test_legacy_building_keys_are_migrated() {
+        let mut value = serde_yaml::to_value(GameCfg::with_defaults()).unwrap();
+        value["game_rules"].as_mapping_mut().unwrap().remove("buildings");
+        value["game_rules"]["investment"]["needs_ap"]["build"] = 5.into();
+        BuildingCatalogue::migrate_legacy(&mut value).unwrap();
+        let cfg: GameCfg = serde_yaml::from_value(value.clone()).unwrap();
+        assert_eq!(cfg.game_rules.buildings.get(Building::Farm).unwrap().ap, 5);
+
+        value["game_rules"]["investment"]["needs_ap"]["build"] = 5.into();
+        assert!(BuildingCatalogue::migrate_legacy(&mut value).is_err());
+    }
+    #[tokio::test]
+    async fn test_build_follows_building_definitions() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.buildings.0[0].max_per_player = 1;
+        let mut game_state = GameState::new();
+        let mut player = Player::with_cfg(&cfg);
+        player.resources.insert(Items::Wood, 6);
+        game_state.players.insert("a", player);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a"];
+        assert!(apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Build(Building::Farm)).await.is_ok());
+        assert_eq!(game_state.players["a"].resources[&Items::Wood], 3);
+        let limited = apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Build(Building::Farm)).await;
+        assert!(matches!(limited, Err(ActionError::BuildingLimit(_))));
+        let upgrade_only = apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Build(Building::SuperFarm)).await;
+        assert!(matches!(upgrade_only, Err(ActionError::CannotBuild(_))));
+
+        // 升级路径引用了未定义的建筑
+        cfg.game_rules.buildings.0.remove(1);
+        assert!(cfg.validate().is_err());
+    }
+    #[tokio::test]
+    async fn test_upkeep_starvation_penalties() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.upkeep.starvation.shut_down_buildings = 1;
+        cfg.game_rules.upkeep.starvation.penalty_points = 1;
+        let mut game_state = GameState::new();
+        let mut fed = Player::with_cfg(&cfg);
+        fed.buildings = vec![Building::Farm];
+        fed.resources.insert(Items::Food, 1);
+        fed.action_points = 0;
+        game_state.players.insert("a", fed);
+        let mut hungry = Player::with_cfg(&cfg);
+        hungry.buildings = vec![Building::Cannon, Building::Miner];
+        hungry.resources.insert(Items::Food, 1);
+        hungry.resources.insert(Items::Ore, 0);
+        hungry.action_points = 0;
+        game_state.players.insert("b", hungry);
+
+        settle_epoch(&mut game_state, &cfg).await;
+        // 农场先产出 1 食物，正好付清固定 1 加农场 1 的维护费
+        assert_eq!(game_state.players["a"].resources[&Items::Food], 0);
+        assert_eq!(game_state.players["a"].penalty_points, 0);
+        let hungry = &game_state.players["b"];
+        assert_eq!(hungry.resources[&Items::Food], 0);
+        assert_eq!(hungry.penalty_points, 1);
+        assert_eq!(hungry.shut_down_buildings, vec![Building::Miner]);
+        assert_eq!(hungry.resources[&Items::Ore], 1);
+        let lose_ap = cfg.game_rules.upkeep.starvation.lose_ap;
+        assert_eq!(hungry.action_points, game_state.players["a"].action_points - lose_ap);
+        let mut receiver = game_state.players["a"].to_channel.receiver.lock().await;
+        let mut starved = None;
+        while let Ok(msg) = receiver.try_recv() {
+            if let ServerToPlayerMessage::Broadcast { raw: ServerBroadcastMessage::Starved { player, missing_food } } = msg {
+                starved = Some((player, missing_food));
+            }
+        }
+        assert_eq!(starved, Some(("b".into(), 2)));
+        drop(receiver);
+
+        // 停工的矿机在下一次结算时不产出
+        settle_epoch(&mut game_state, &cfg).await;
+        assert_eq!(game_state.players["b"].resources[&Items::Ore], 1);
+    }
+    #[tokio::test]
+    async fn test_ap_refill_carry_over_and_bonuses() {
+        let cfg = GameCfg::with_defaults();
+        let ap = &cfg.game_rules.ap;
+        let mut game_state = GameState::new();
+        let mut low = Player::with_cfg(&cfg);
+        low.buildings = vec![Building::Bank];
+        low.action_points = ap.carry_over_cap + 3;
+        game_state.players.insert("a", low);
+        let mut high = Player::with_cfg(&cfg);
+        high.action_points = 1;
+        game_state.players.insert("b", high);
+        game_state.bids = [("a", 1), ("b", 3)].into();
+
+        settle_epoch(&mut game_state, &cfg).await;
+        // 剩余行动点按上限保留，银行和最低出价各有奖励
+        let refill = ap.base_refill + ap.building_bonus[&Building::Bank] + ap.last_bid_bonus;
+        assert_eq!(game_state.players["a"].action_points, ap.carry_over_cap + refill);
+        assert_eq!(game_state.players["b"].action_points, 1 + ap.base_refill);
+        let update = game_state.players["a"].to_channel.receiver.lock().await.try_recv().unwrap();
+        assert!(matches!(update, ServerToPlayerMessage::PlayerStateUpdate { carried_over, refill: x, .. } if carried_over == ap.carry_over_cap && x == refill));
+
+        // 所有人出价相同时没有最低出价奖励
+        game_state.bids = [("a", 2), ("b", 2)].into();
+        game_state.players.get_mut("b").unwrap().action_points = 0;
+        settle_epoch(&mut game_state, &cfg).await;
+        assert_eq!(game_state.players["b"].action_points, ap.base_refill);
+    }
+    #[tokio::test]
+    async fn test_upgrade_follows_paths() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.upgrades.paths[0].requires = vec![Building::Bank];
+        let mut game_state = GameState::new();
+        let mut player = Player::with_cfg(&cfg);
+        player.buildings = vec![Building::Farm];
+        player.resources.insert(Items::Wood, 3);
+        player.resources.insert(Items::Gold, 1);
+        player.action_points = 5;
+        game_state.players.insert("a", player);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a"];
+        let upgrade = |building| InvestmentAction::Upgrade(building);
+        let not_owned = apply_investment(&mut game_state, &cfg, "a", upgrade(Building::Miner)).await;
+        assert!(matches!(not_owned, Err(ActionError::MissingBuilding(Building::Miner))));
+        let requires = apply_investment(&mut game_state, &cfg, "a", upgrade(Building::Farm)).await;
+        assert!(matches!(requires, Err(ActionError::MissingBuilding(Building::Bank))));
+        game_state.players.get_mut("a").unwrap().buildings.push(Building::Bank);
+        let too_poor = apply_investment(&mut game_state, &cfg, "a", upgrade(Building::Farm)).await;
+        assert!(matches!(too_poor, Err(ActionError::NotEnoughItems(Items::Gold))));
+        assert_eq!(game_state.players["a"].action_points, 5);
+
+        game_state.players.get_mut("a").unwrap().resources.insert(Items::Gold, 2);
+        let outcome = apply_investment(&mut game_state, &cfg, "a", upgrade(Building::Farm)).await.unwrap();
+        assert!(matches!(outcome, ActionOutcome::Upgraded { from: Building::Farm, to: Building::SuperFarm }));
+        let player = &game_state.players["a"];
+        assert_eq!(player.buildings, vec![Building::SuperFarm, Building::Bank]);
+        assert_eq!(player.resources[&Items::Wood], 0);
+        assert_eq!(player.resources[&Items::Gold], 0);
+        assert_eq!(player.action_points, 3);
+        let top = apply_investment(&mut game_state, &cfg, "a", upgrade(Building::SuperFarm)).await;
+        assert!(matches!(top, Err(ActionError::CannotUpgrade(Building::SuperFarm))));
+
+        // 升级不能绕过目标建筑的数量上限
+        cfg.game_rules.buildings.0.iter_mut().find(|x| x.id == Building::SuperFarm).unwrap().max_per_player = 1;
+        let player = game_state.players.get_mut("a").unwrap();
+        player.buildings.push(Building::Farm);
+        player.resources.insert(Items::Wood, 3);
+        player.resources.insert(Items::Gold, 2);
+        let limit = apply_investment(&mut game_state, &cfg, "a", upgrade(Building::Farm)).await;
+        assert!(matches!(limit, Err(ActionError::BuildingLimit(Building::SuperFarm))));
+        assert_eq!(game_state.players["a"].buildings, vec![Building::SuperFarm, Building::Bank, Building::Farm]);
+    }
+    #[tokio::test]
+    async fn test_attack_validates_aim_and_cannon_halves_damage() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.combat.building_hit_percent = 100;
+        cfg.game_rules.combat.stockpile_hit_percent = 100;
+        cfg.game_rules.combat.max_attacks_per_epoch = 5;
+        cfg.game_rules.bank.safe_from_losses = false;
+        let mut game_state = GameState::new();
+        let mut attacker = Player::with_cfg(&cfg);
+        attacker.buildings = vec![Building::Cannon];
+        attacker.resources.insert(Items::Iron, 10);
+        attacker.action_points = 10;
+        game_state.players.insert("a", attacker);
+        let mut defender = Player::with_cfg(&cfg);
+        defender.buildings = vec![Building::Farm; 3];
+        defender.resources.insert(Items::Wood, 10);
+        game_state.players.insert("b", defender);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a", "b"];
+        let attack = |aim| InvestmentAction::Attack { target: "b".to_string(), aim };
+
+        // 对方没有银行，先拒绝再扣费
+        let missing = apply_investment(&mut game_state, &cfg, "a", attack(AttackTarget::Building(Building::Bank))).await;
+        assert!(matches!(missing, Err(ActionError::TargetMissingBuilding(_, Building::Bank))));
+        assert_eq!(game_state.players["a"].action_points, 10);
+        assert_eq!(game_state.players["a"].resources[&Items::Iron], 10);
+
+        let outcome = apply_investment(&mut game_state, &cfg, "a", attack(AttackTarget::Building(Building::Farm))).await.unwrap();
+        assert!(matches!(outcome, ActionOutcome::Attacked { hit: true, destroyed: 2 }));
+        assert_eq!(game_state.players["a"].resources[&Items::Iron], 8);
+
+        // 有炮台防守时命中率不变，摧毁数量减半
+        game_state.players.get_mut("b").unwrap().buildings.extend([Building::Farm, Building::Cannon]);
+        let outcome = apply_investment(&mut game_state, &cfg, "a", attack(AttackTarget::Building(Building::Farm))).await.unwrap();
+        assert!(matches!(outcome, ActionOutcome::Attacked { hit: true, destroyed: 1 }));
+        let outcome = apply_investment(&mut game_state, &cfg, "a", attack(AttackTarget::Stockpile(Items::Wood))).await.unwrap();
+        assert!(matches!(outcome, ActionOutcome::Attacked { hit: true, destroyed: 2 }));
+        assert_eq!(game_state.players["b"].resources[&Items::Wood], 8);
+
+        // 存款不受保护时，手上的金币不够再从存款里扣，并记进存款记录
+        let defender = game_state.players.get_mut("b").unwrap();
+        defender.resources.insert(Items::Gold, 1);
+        defender.bank_money = 5;
+        let outcome = apply_investment(&mut game_state, &cfg, "a", attack(AttackTarget::Stockpile(Items::Gold))).await.unwrap();
+        assert!(matches!(outcome, ActionOutcome::Attacked { hit: true, destroyed: 2 }));
+        let defender = &game_state.players["b"];
+        assert_eq!(defender.bank_money, 4);
+        let record = defender.bank_history.last().unwrap();
+        assert!(matches!(record.kind, BankRecordKind::Attacked));
+        assert_eq!((record.amount, record.balance), (-1, 4));
+    }
+    #[test]
+    fn test_validate_checks_item_and_building_references() {
+        assert!(GameCfg::with_defaults().validate().is_ok());
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.ap.building_bonus.insert(Building::Custom("lighthouse"), 1);
+        assert!(cfg.validate().is_err());
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.items.0.retain(|x| x.id != Items::Diamond);
+        assert!(cfg.validate().is_err(), "explore event still awards diamonds");
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.items.0.retain(|x| x.id != Items::Iron);
+        cfg.game_rules.buildings.0.iter_mut().for_each(|x| { x.cost.remove(&Items::Iron); });
+        cfg.game_rules.upgrades.paths.clear();
+        cfg.game_rules.investment.production.mine_ore_cost.remove(&Items::Iron);
+        assert!(cfg.validate().is_err(), "iron is hard-coded for attacks");
+    }
+    #[tokio::test]
+    async fn test_mine_rejects_overflowing_amount() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        let mut player = Player::with_cfg(&cfg);
+        player.buildings.push(Building::SuperMiner);
+        game_state.players.insert("a", player);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a"];
+        // 每个金币消耗 2 个矿石，2^31 个金币的消耗会溢出成 0
+        let action = InvestmentAction::Mine { into: Items::Gold, amount: 1 << 31 };
+        let result = apply_investment(&mut game_state, &cfg, "a", action).await;
+        assert!(matches!(result, Err(ActionError::AmountTooLarge(_))));
+        assert_eq!(game_state.players["a"].resources[&Items::Gold], 0);
+
+        game_state.players.get_mut("a").unwrap().resources.insert(Items::Ore, 2);
+        let action = InvestmentAction::Mine { into: Items::Gold, amount: 1 };
+        let outcome = apply_investment(&mut game_state, &cfg, "a", action).await.unwrap();
+        assert!(matches!(outcome, ActionOutcome::Produced { .. }));
+        assert_eq!(game_state.players["a"].resources[&Items::Gold], 2);
+    }
+    #[tokio::test]
+    async fn test_pick_and_ore_use_building_bonuses() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.investment.production.farm_food_bonus.insert(Building::SuperFarm, 3);
+        let mut game_state = GameState::new();
+        let mut player = Player::with_cfg(&cfg);
+        player.action_points = 10;
+        game_state.players.insert("a", player);
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a"];
+        let count = |game_state: &GameState, item| game_state.players["a"].item_count(item);
+        let food = count(&game_state, Items::Food);
+        let iron = apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Pick { item: Items::Iron }).await;
+        assert!(matches!(iron, Err(ActionError::CannotPick(Items::Iron))));
+        apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Pick { item: Items::Food }).await.unwrap();
+        assert_eq!(count(&game_state, Items::Food), food + 2);
+        // 农场按配置加成，无敌农场的加成也来自配置
+        game_state.players.get_mut("a").unwrap().buildings = vec![Building::Farm, Building::Farm, Building::SuperFarm];
+        apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Pick { item: Items::Food }).await.unwrap();
+        assert_eq!(count(&game_state, Items::Food), food + 2 + 2 + 2 + 3);
+        // 木材不吃农场加成
+        let wood = count(&game_state, Items::Wood);
+        apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Pick { item: Items::Wood }).await.unwrap();
+        assert_eq!(count(&game_state, Items::Wood), wood + 1);
+
+        let ore = count(&game_state, Items::Ore);
+        apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Ore).await.unwrap();
+        assert_eq!(count(&game_state, Items::Ore), ore + 1);
+        game_state.players.get_mut("a").unwrap().buildings.push(Building::Miner);
+        let outcome = apply_investment(&mut game_state, &cfg, "a", InvestmentAction::Ore).await.unwrap();
+        assert!(matches!(outcome, ActionOutcome::Produced { produced, .. } if produced[&Items::Ore] == 2));
+        assert_eq!(count(&game_state, Items::Ore), ore + 3);
+    }
+    #[test]
+    fn test_locale_negotiation_and_messages() {
+        assert_eq!(Locale::negotiate(None, Some("fr;q=1, en-US;q=0.8, zh;q=0.5")), Locale::En);
+        assert_eq!(Locale::negotiate(Some("zh-CN"), Some("en")), Locale::Zh);
+        assert_eq!(Locale::negotiate(Some("xx"), None), Locale::Zh);
+
+        GameCfg::with_defaults().register_names();
+        let err = ActionError::NotEnoughItems(Items::Gold);
+        assert_eq!(err.message(Locale::Zh), "金币不足");
+        assert_eq!(err.message(Locale::En), "Not enough Gold");
+    }
+    #[test]
+    fn test_wire_ids_and_legacy_labels() {
+        let mut game_state = GameState::new();
+        game_state.market = vec![Items::Gold];
+        game_state.resource_values.insert(Items::Wood, 2);
+        let response = GameStateResponse::from(&game_state);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["market"][0], "gold");
+        assert_eq!(json["values"]["wood"], 2);
+        let legacy = with_legacy_labels(true, || serde_json::to_value(&response).unwrap());
+        assert_eq!(legacy["market"][0], "金币");
+        assert_eq!(legacy["values"]["木材"], 2);
+
+        assert_eq!(serde_json::to_value(Building::SuperFarm).unwrap(), "super_farm");
+        assert_eq!(serde_json::from_str::<Building>("\"无敌农场\"").unwrap(), Building::SuperFarm);
+        // 反序列化和 TryFrom 走同一套中文名称匹配，包括自定义建筑和老版本的模糊写法
+        let lighthouse = Building::register("lighthouse", "灯塔");
+        let legacy = with_legacy_labels(true, || serde_json::to_value(lighthouse).unwrap());
+        assert_eq!(legacy, "灯塔");
+        assert_eq!(serde_json::from_value::<Building>(legacy).unwrap(), lighthouse);
+        assert_eq!(Building::try_from("灯塔").unwrap(), lighthouse);
+        assert_eq!(serde_json::from_str::<Building>("\"超级矿机\"").unwrap(), Building::try_from("超级矿机").unwrap());
+        assert!(serde_json::from_str::<Items>("\"灯塔\"").is_err());
+    }
+    #[test]
+    fn test_msgpack_frames_use_same_messages() {
+        let value = serde_json::json!({"type": "bid", "target": {"action": {"type": "placebid", "target": 3}}});
+        let Frame::Binary(bytes) = Encoding::Msgpack.encode(&value).unwrap() else {
+            panic!("msgpack should produce binary frames");
+        };
+        let msg = codec::decode_binary::<PlayerToServerMessage>(&bytes).unwrap();
+        assert!(matches!(msg, PlayerToServerMessage::Bid { action: BidAction::PlaceBid(3) }));
+        assert!(matches!(Encoding::Json.encode(&value).unwrap(), Frame::Text(_)));
+    }
+    #[test]
+    fn test_schema_covers_messages_and_responses() {
+        let schema = resource_island_server::schema::schema();
+        let outbound = serde_json::to_string(&schema["outbound"]["ServerToPlayerMessage"]).unwrap();
+        assert!(outbound.contains("\"welcome\""));
+        assert!(outbound.contains("\"statediff\""));
+        assert!(schema["inbound"]["PlayerToServerMessage"].is_object());
+        for (route, entry) in schema["http"].as_object().unwrap() {
+            let entry = entry.as_object().unwrap();
+            assert_eq!(entry.len(), 3, "{}", route);
+            assert!(entry.contains_key("request") && entry.contains_key("response"), "{}", route);
+            assert_eq!(route.starts_with("GET "), entry["request"].is_null(), "{}", route);
+            // 鉴权失败对所有路由都适用
+            assert!(entry["errors"].get("401").is_some() && entry["errors"].get("403").is_some(), "{}", route);
+        }
+        let http = &schema["http"];
+        assert_eq!(http["GET /"]["response"]["type"], "string");
+        assert!(http["GET /schema"]["response"].is_object());
+        assert_eq!(http["GET /events"]["errors"]["409"]["type"], "string");
+        assert!(http["GET /gamestate"]["response"]["properties"]["values"].is_object());
+        assert!(http["POST /actions"]["response"].is_null());
+        assert_eq!(http["POST /actions"]["request"], schema["inbound"]["PlayerToServerMessage"]);
+        let action = &http["POST /players/{player_name}/actions"];
+        assert_eq!(action["request"], schema["inbound"]["PlayerToServerMessage"]);
+        assert_eq!(action["response"], schema["outbound"]["ServerToPlayerMessage"]);
+        assert_eq!(action["errors"]["422"]["anyOf"][0], schema["outbound"]["ServerToPlayerMessage"]);
+    }
+    #[test]
+    fn test_server_messages_round_trip_for_clients() {
+        use resource_island_server::dtos::{PlayerInfoResponse, RulesSummary, TradeOfferResponse};
+        use resource_island_server::enums::with_unknown_ids;
+        let cfg = GameCfg::with_defaults();
+        let pearl = Items::register("pearl", "珍珠");
+        let mut game_state = GameState::new();
+        game_state.players.insert("小明", Player::with_cfg(&cfg));
+        game_state.market = vec![Items::Gold, pearl];
+        game_state.resource_values.insert(pearl, 7);
+        game_state.waiting_on = vec!["小明"];
+        let mut player = Player::with_cfg(&cfg);
+        player.buildings.push(Building::Bank);
+        let offer = TradeOfferResponse {
+            id: 1,
+            from: "小明".into(),
+            to: "小红".into(),
+            give: [(pearl, 1)].into(),
+            want: [(Items::Gold, 2)].into(),
+        };
+        let broadcasts = vec![
+            ServerBroadcastMessage::PhaseChanged { epoch: 1, phase: PHASE_BID, remaining_secs: Some(30) },
+            ServerBroadcastMessage::TurnChanged { player: "小明".into(), remaining_secs: None },
+            ServerBroadcastMessage::TimedOut { player: "小明".into() },
+            ServerBroadcastMessage::PlayerAfk { player: "小明".into() },
+            ServerBroadcastMessage::DataRequired { epoch: 1, phase: PHASE_TAKE },
+            ServerBroadcastMessage::WaitingOn { players: vec!["小明".into(), "小红".into()] },
+            ServerBroadcastMessage::GameStart,
+            ServerBroadcastMessage::Lobby { host: Some("小明".into()), ready: vec!["小红".into()] },
+            ServerBroadcastMessage::Countdown { seconds: 5 },
+            ServerBroadcastMessage::CountdownCancelled,
+            ServerBroadcastMessage::TradeCompleted { offer: offer.clone() },
+            ServerBroadcastMessage::Attack { attacker: "小明".into(), target: "小红".into(), aim: AttackTarget::Stockpile(pearl), hit: true, destroyed: 2 },
+            ServerBroadcastMessage::Starved { player: "小红".into(), missing_food: 3 },
+        ];
+        let outcomes = vec![
+            ActionOutcome::Done,
+            ActionOutcome::TradeProposed { id: 1 },
+            ActionOutcome::Exchanged { from: Items::Wood, from_amount: 2, to: pearl, to_amount: 1, fee_percent: 0 },
+            ActionOutcome::Produced { consumed: [(Items::Ore, 2)].into(), produced: [(pearl, 1)].into() },
+            ActionOutcome::Attacked { hit: false, destroyed: 0 },
+            ActionOutcome::Upgraded { from: Building::Farm, to: Building::SuperFarm },
+            ActionOutcome::Explored { event: "storm".to_string(), gained: HashMap::new(), lost: [(Items::Wood, 1)].into() },
+        ];
+        let mut sent = vec![
+            ServerToPlayerMessage::Welcome {
+                server_version: "1.0.0".into(),
+                protocol_version: 1,
+                rules_summary: RulesSummary::from(&cfg),
+                session_token: "abc".to_string(),
+            },
+            ServerToPlayerMessage::Snapshot {
+                version: 3,
+                state: GameStateResponse::from(&game_state).localized(Locale::En),
+                player: Some(PlayerInfoResponse::from(&player).localized(Locale::En)),
+            },
+            ServerToPlayerMessage::StateDiff {
+                version: 4,
+                diffs: vec![
+                    StateDiff::Phase { epoch: 2, phase: PHASE_INVESTMENT },
+                    StateDiff::Players { players: vec!["小明".into()] },
+                    StateDiff::Market { market: vec![pearl] },
+                    StateDiff::Prices { values: [(pearl, 8)].into() },
+                    StateDiff::ActionPoints { player: "小明".into(), action_points: 2 },
+                    StateDiff::Buildings { player: "小明".into(), buildings: vec![Building::Farm] },
+                    StateDiff::Resources { player: "小明".into(), resources: [(pearl, 2)].into() },
+                    StateDiff::BankMoney { player: "小明".into(), bank_money: 5 },
+                ],
+            },
+            ServerToPlayerMessage::ActionRejected { reason: "no".to_string() },
+            ServerToPlayerMessage::TradeOffered { offer },
+            ServerToPlayerMessage::PlayerStateUpdate { carried_over: 1, refill: 3, info: PlayerInfoResponse::from(&player) },
+            ServerToPlayerMessage::TradeRejected { id: 1, by: "小红".into() },
+        ];
+        sent.extend(broadcasts.into_iter().map(|raw| ServerToPlayerMessage::Broadcast { raw }));
+        sent.extend(outcomes.into_iter().map(|outcome| ServerToPlayerMessage::ActionAccepted { outcome }));
+        for msg in sent {
+            let json = serde_json::to_value(&msg).unwrap();
+            let text = json.to_string();
+            let decoded = with_unknown_ids(|| codec::decode_text::<ServerToPlayerMessage>(text.as_str())).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+        }
+        // 客户端不认识的物品 id 原样保留，不会写进服务器的注册表；服务端解析时仍然拒绝
+        let diff = serde_json::json!({"type": "market", "target": {"market": ["amber"]}});
+        let decoded = with_unknown_ids(|| serde_json::from_value::<StateDiff>(diff.clone())).unwrap();
+        assert!(matches!(decoded, StateDiff::Market { market } if market == [Items::Custom("amber")]));
+        assert!(Items::from_id("amber").is_none());
+        assert!(serde_json::from_value::<StateDiff>(diff).is_err());
+
+        let action = InvestmentAction::Exchange { from: Items::Wood, to: pearl, amount: 3 };
+        let json = serde_json::to_value(PlayerToServerMessage::Investment { action }).unwrap();
+        let decoded = codec::decode_text::<PlayerToServerMessage>(json.to_string().as_str()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+    }
+    /// 在随机端口上启动路由，返回地址
+    async fn serve(state: Arc<AppState>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router(state)).await.unwrap() });
+        address
+    }
+    /// 读 SSE 响应直到出现 `type` 相符的事件，返回它的 JSON
+    async fn next_sse_event(response: &mut reqwest::Response, buffer: &mut String, kind: &str) -> serde_json::Value {
+        let read = async {
+            loop {
+                while let Some(end) = buffer.find("\n\n") {
+                    let event: String = buffer.drain(..end + 2).collect();
+                    let Some(data) = event.lines().find_map(|x| x.strip_prefix("data:")) else {
+                        continue;
+                    };
+                    let json: serde_json::Value = serde_json::from_str(data.trim()).unwrap();
+                    if json["type"] == kind {
+                        return json;
+                    }
+                }
+                let chunk = response.chunk().await.unwrap().expect("event stream ended");
+                buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), read).await.unwrap()
+    }
+    #[tokio::test]
+    async fn test_sse_player_gets_welcome_and_posts_actions() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        game_state.initialize(&cfg).await;
+        let address = serve(Arc::new(AppState::new(cfg, game_state))).await;
+        let http = reqwest::Client::new();
+        let mut events = http.get(format!("{address}/events?token=set_the_token_here&player=alice")).send().await.unwrap();
+        assert_eq!(events.status(), 200);
+        let mut buffer = String::new();
+        let welcome = next_sse_event(&mut events, &mut buffer, "welcome").await;
+        let session_token = welcome["target"]["session_token"].as_str().unwrap().to_string();
+
+        let post = |session_token: Option<&str>| {
+            let request = http.post(format!("{address}/actions?token=set_the_token_here")).json(&serde_json::json!({"type": "requestsnapshot"}));
+            match session_token {
+                Some(session_token) => request.header("x-session-token", session_token),
+                None => request,
+            }
+        };
+        assert_eq!(post(None).send().await.unwrap().status(), 401);
+        assert_eq!(post(Some("guess")).send().await.unwrap().status(), 403);
+        assert_eq!(post(Some(session_token.as_str())).send().await.unwrap().status(), 202);
+        // 操作结果不在响应里，而是从事件流推送
+        let snapshot = next_sse_event(&mut events, &mut buffer, "snapshot").await;
+        assert!(snapshot["target"]["state"]["players"].as_array().unwrap().iter().any(|x| x == "alice"));
+        assert!(snapshot["target"]["player"].is_object());
+
+        // 同名玩家已经在线，不能再开一条事件流
+        let again = http.get(format!("{address}/events?token=set_the_token_here&player=alice")).send().await.unwrap();
+        assert_eq!(again.status(), 409);
+    }
+    #[tokio::test]
+    async fn test_player_action_endpoint_answers_in_response() {
+        let cfg = GameCfg::with_defaults();
+        let mut game_state = GameState::new();
+        game_state.initialize(&cfg).await;
+        let state = Arc::new(AppState::new(cfg, game_state));
+        tokio::spawn(resource_island_server::game::game_main_loop(state.clone()));
+        let address = serve(state.clone()).await;
+        let http = reqwest::Client::new();
+        let mut events = http.get(format!("{address}/events?token=set_the_token_here&player=alice&lang=en")).send().await.unwrap();
+        let welcome = next_sse_event(&mut events, &mut String::new(), "welcome").await;
+        let session_token = welcome["target"]["session_token"].as_str().unwrap().to_string();
+
+        let post = |player: &str, session_token: &str, msg: serde_json::Value| {
+            http.post(format!("{address}/players/{player}/actions?token=set_the_token_here"))
+                .header("x-session-token", session_token)
+                .json(&msg)
+                .send()
+        };
+        let ready = post("alice", session_token.as_str(), serde_json::json!({"type": "ready"})).await.unwrap();
+        assert_eq!(ready.status(), 200);
+        let ready: serde_json::Value = ready.json().await.unwrap();
+        assert_eq!(ready, serde_json::json!({"type": "actionaccepted", "target": {"outcome": {"type": "done"}}}));
+        assert_eq!(state.game_state.read().await.ready_players(), vec!["alice"]);
+
+        // 大厅里还不能出价，拒绝原因按玩家的语言返回
+        let bid = post("alice", session_token.as_str(), serde_json::json!({"type": "bid", "target": {"action": {"type": "placebid", "target": 1}}})).await.unwrap();
+        assert_eq!(bid.status(), 422);
+        let bid: serde_json::Value = bid.json().await.unwrap();
+        assert_eq!(bid["type"], "actionrejected");
+        assert_eq!(bid["target"]["reason"], "The game has not started yet");
+
+        assert_eq!(post("alice", "guess", serde_json::json!({"type": "ready"})).await.unwrap().status(), 403);
+        assert_eq!(post("bob", session_token.as_str(), serde_json::json!({"type": "ready"})).await.unwrap().status(), 404);
+    }
+    #[tokio::test]
+    async fn test_spectators_get_delayed_public_diffs_only() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.server.spectator.delay_secs = 1;
+        let mut game_state = GameState::new();
+        game_state.initialize(&cfg).await;
+        game_state.register_player("a".to_string(), Player::with_cfg(&cfg)).await.unwrap();
+        game_state.publish_diffs().await;
+        let state = Arc::new(AppState::new(cfg, game_state));
+        let address = serve(state.clone()).await;
+        let joined = std::time::Instant::now();
+        let mut events = reqwest::get(format!("{address}/events?token=set_the_token_here")).await.unwrap();
+        assert_eq!(events.status(), 200);
+        {
+            let mut game_state = state.game_state.write().await;
+            game_state.market.push(Items::Gold);
+            let player = game_state.players.get_mut("a").unwrap();
+            player.resources.insert(Items::Gold, 9);
+            player.bank_money = 3;
+            game_state.publish_diffs().await;
+        }
+        let published = std::time::Instant::now();
+        let mut buffer = String::new();
+        // 进入观战时的快照也要等满延迟
+        next_sse_event(&mut events, &mut buffer, "snapshot").await;
+        assert!(joined.elapsed() >= std::time::Duration::from_secs(1));
+        let diff = next_sse_event(&mut events, &mut buffer, "statediff").await;
+        assert!(published.elapsed() >= std::time::Duration::from_secs(1));
+        // 资源和存款只发给玩家本人
+        let kinds = diff["target"]["diffs"].as_array().unwrap().iter().map(|x| x["type"].as_str().unwrap()).collect::<Vec<&str>>();
+        assert_eq!(kinds, vec!["market"]);
+    }
+    /// 像 HTTP 操作接口一样把消息交给游戏主循环，等待处理结果
+    async fn request(state: &AppState, player: &str, msg: PlayerToServerMessage) -> ServerToPlayerMessage {
+        let sender = state.game_state.read().await.players[player].from_channel.sender.clone();
+        let (respond_to, response) = tokio::sync::oneshot::channel();
+        sender.send(PlayerRequest { msg, respond_to: Some(respond_to) }).await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), response).await.unwrap().unwrap()
+    }
+    /// 从玩家的推送队列里找第一条符合条件的广播
+    async fn wait_broadcast(state: &AppState, player: &str, found: impl Fn(&ServerBroadcastMessage) -> bool) -> ServerBroadcastMessage {
+        let receiver = state.game_state.read().await.players[player].to_channel.receiver.clone();
+        let wait = async {
+            loop {
+                if let Some(ServerToPlayerMessage::Broadcast { raw }) = receiver.lock().await.recv().await
+                    && found(&raw)
+                {
+                    return raw;
+                }
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), wait).await.unwrap()
+    }
+    #[tokio::test]
+    async fn test_lobby_start_needs_host_and_ready_players() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.server.player_numbers = 3;
+        cfg.server.lobby.countdown_secs = 60;
+        let mut game_state = GameState::new();
+        game_state.initialize(&cfg).await;
+        for name in ["a", "b"] {
+            game_state.register_player(name.to_string(), Player::with_cfg(&cfg)).await.unwrap();
+        }
+        let state = Arc::new(AppState::new(cfg, game_state));
+        tokio::spawn(resource_island_server::game::game_main_loop(state.clone()));
+        let rejected = |msg: ServerToPlayerMessage, err: ActionError| {
+            assert!(matches!(msg, ServerToPlayerMessage::ActionRejected { reason } if reason == err.message(Locale::Zh)));
+        };
+
+        // 第一个加入的玩家是房主
+        rejected(request(&state, "b", PlayerToServerMessage::StartGame).await, ActionError::NotHost);
+        assert!(matches!(request(&state, "a", PlayerToServerMessage::Ready).await, ServerToPlayerMessage::ActionAccepted { .. }));
+        rejected(request(&state, "a", PlayerToServerMessage::StartGame).await, ActionError::NotEnoughReady(1, 2));
+        assert!(matches!(request(&state, "b", PlayerToServerMessage::Ready).await, ServerToPlayerMessage::ActionAccepted { .. }));
+        assert!(matches!(request(&state, "a", PlayerToServerMessage::StartGame).await, ServerToPlayerMessage::ActionAccepted { .. }));
+        wait_broadcast(&state, "b", |x| matches!(x, ServerBroadcastMessage::Countdown { seconds: 60 })).await;
+
+        // 准备人数不够后倒计时取消，游戏不会开始
+        assert!(matches!(request(&state, "b", PlayerToServerMessage::Unready).await, ServerToPlayerMessage::ActionAccepted { .. }));
+        wait_broadcast(&state, "b", |x| matches!(x, ServerBroadcastMessage::CountdownCancelled)).await;
+        assert!(!state.game_state.read().await.started);
+    }
+    #[tokio::test]
+    async fn test_lobby_rejects_players_beyond_player_numbers() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.server.player_numbers = 2;
+        let mut game_state = GameState::new();
+        game_state.initialize(&cfg).await;
+        let address = serve(Arc::new(AppState::new(cfg, game_state))).await;
+        let join = |player: &str| reqwest::get(format!("{address}/events?token=set_the_token_here&lang=en&player={player}"));
+        let _alice = join("alice").await.unwrap();
+        let _bob = join("bob").await.unwrap();
+        // 需要全员准备时人满也不会开局，第三个人仍然不能加入
+        let carol = join("carol").await.unwrap();
+        assert_eq!(carol.status(), 409);
+        assert_eq!(carol.text().await.unwrap(), JoinError::LobbyFull(2).message(Locale::En));
+    }
+    /// 取出玩家队列里已经收到的广播
+    async fn drain_broadcasts(game_state: &GameState, player: &str) -> Vec<ServerBroadcastMessage> {
+        let mut receiver = game_state.players[player].to_channel.receiver.lock().await;
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .filter_map(|x| match x {
+                ServerToPlayerMessage::Broadcast { raw } => Some(raw),
+                _ => None,
+            })
+            .collect()
+    }
+    #[tokio::test]
+    async fn test_timeouts_apply_defaults_and_mark_afk() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.game_rules.timers.afk_after_timeouts = 2;
+        let mut game_state = GameState::new();
+        for name in ["a", "b"] {
+            game_state.register_player(name.to_string(), Player::with_cfg(&cfg)).await.unwrap();
+        }
+        // 出价超时按 0 出价，所有没出价的玩家一起处理
+        game_state.phase = PHASE_BID;
+        game_state.waiting_on = vec!["a", "b"];
+        game_state.deadline = Some(tokio::time::Instant::now());
+        apply_timeouts(&mut game_state, &cfg).await;
+        assert!(game_state.waiting_on.is_empty());
+        assert_eq!((game_state.bids["a"], game_state.bids["b"]), (0, 0));
+        assert!(drain_broadcasts(&game_state, "b").await.iter().any(|x| matches!(x, ServerBroadcastMessage::TimedOut { player } if player == "a")));
+
+        // 拿取超时只结束当前玩家的回合，第二次超时后标记为挂机
+        game_state.phase = PHASE_TAKE;
+        game_state.market = vec![Items::Wood];
+        game_state.waiting_on = vec!["a", "b"];
+        game_state.deadline = Some(tokio::time::Instant::now());
+        apply_timeouts(&mut game_state, &cfg).await;
+        assert_eq!(game_state.waiting_on, vec!["b"]);
+        assert!(game_state.players["a"].afk);
+        assert!(!game_state.players["b"].afk);
+        assert!(drain_broadcasts(&game_state, "b").await.iter().any(|x| matches!(x, ServerBroadcastMessage::PlayerAfk { player } if player == "a")));
+
+        // 挂机玩家不用等到超时就按结束投资处理，其他人继续等待
+        game_state.phase = PHASE_INVESTMENT;
+        game_state.waiting_on = vec!["a", "b"];
+        game_state.deadline = None;
+        apply_timeouts(&mut game_state, &cfg).await;
+        assert_eq!(game_state.waiting_on, vec!["b"]);
+        assert_eq!(game_state.players["a"].timeouts, 2);
+
+        // 发来任何消息都会清掉挂机标记
+        handle_game_message(&mut game_state, &cfg, "a", PlayerToServerMessage::RequestSnapshot).await.unwrap();
+        assert!(!game_state.players["a"].afk);
+        assert_eq!(game_state.players["a"].timeouts, 0);
+        game_state.waiting_on = vec!["a", "b"];
+        apply_timeouts(&mut game_state, &cfg).await;
+        assert_eq!(game_state.waiting_on, vec!["a", "b"]);
+    }
+    #[tokio::test]
+    async fn test_waiting_players_are_listed_and_reprompted() {
+        let mut cfg = GameCfg::with_defaults();
+        cfg.server.player_numbers = 2;
+        cfg.server.lobby.require_ready = false;
+        cfg.server.lobby.countdown_secs = 0;
+        cfg.game_rules.timers.reprompt_secs = 1;
+        let mut game_state = GameState::new();
+        game_state.initialize(&cfg).await;
+        for name in ["a", "b"] {
+            game_state.register_player(name.to_string(), Player::with_cfg(&cfg)).await.unwrap();
+        }
+        let state = Arc::new(AppState::new(cfg, game_state));
+        tokio::spawn(resource_island_server::game::game_main_loop(state.clone()));
+        let waiting_on = |x: &ServerBroadcastMessage| matches!(x, ServerBroadcastMessage::WaitingOn { .. });
+        let data_required = |x: &ServerBroadcastMessage| matches!(x, ServerBroadcastMessage::DataRequired { phase: PHASE_BID, .. });
+
+        wait_broadcast(&state, "a", data_required).await;
+        let first = wait_broadcast(&state, "b", waiting_on).await;
+        assert!(matches!(first, ServerBroadcastMessage::WaitingOn { players } if players == ["a", "b"]));
+        let bid = PlayerToServerMessage::Bid { action: BidAction::PlaceBid(0) };
+        assert!(matches!(request(&state, "a", bid).await, ServerToPlayerMessage::ActionAccepted { .. }));
+        // 只列出还没出价的玩家
+        let next = wait_broadcast(&state, "b", waiting_on).await;
+        assert!(matches!(next, ServerBroadcastMessage::WaitingOn { players } if players == ["b"]));
+
+        // reprompt_secs 之后只提醒还没出价的玩家
+        wait_broadcast(&state, "b", data_required).await;
+        let game_state = state.game_state.read().await;
+        assert!(!drain_broadcasts(&game_state, "a").await.iter().any(data_required));
+    }
+}